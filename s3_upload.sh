@@ -8,4 +8,4 @@ s3cmd sync --progress --acl-public --add-header 'Cache-Control: max-age=43200' $
 s3cmd sync --progress --acl-public --add-header 'Cache-Control: max-age=43200' $OUTPUTDIR/ s3://$S3_BUCKET --exclude '*.*' --include '*.js' -m "text/javascript"
 
 # sync non gzipped files
-s3cmd sync --progress --acl-public $OUTPUTDIR/ s3://$S3_BUCKET --add-header 'Cache-Control: max-age=86400' --exclude '*.sh' --exclude '*.html' --exclude '*.js' --exclude '*.css'  --exclude '*.gz'
+s3cmd sync --progress --acl-public $OUTPUTDIR/ s3://$S3_BUCKET --add-header 'Cache-Control: max-age=86400' --exclude '*.sh' --exclude '*.html' --exclude '*.js' --exclude '*.css'  --exclude '*.gz' --exclude '.kaihan-cache.json'
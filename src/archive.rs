@@ -0,0 +1,541 @@
+use crate::diagnostics::Diagnostics;
+use crate::{RawFile, StaticContent};
+use anyhow::{bail, Result};
+use log::{info, warn};
+use lol_html::html_content::ContentType;
+use lol_html::html_content::Element;
+use lol_html::{element, text, HandlerResult, RewriteStrSettings};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write as _;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+use url::Url;
+
+/// Directory in the blog holding all snapshots, which are published under the same path.
+const ARCHIVE_DIR: &str = "archive";
+
+/// Remembers which links were archived, so that each of them is only fetched once.
+const MANIFEST: &str = "manifest.json";
+
+/// Larger pages and assets aren't archived.
+const MAX_SIZE: u64 = 20 << 20;
+
+/// Where the snapshot of `url` is published.
+pub fn snapshot_url(url: &str) -> String {
+    format!("/{:}/{:}/", ARCHIVE_DIR, short_hash(url))
+}
+
+fn short_hash(s: &str) -> String {
+    blake3::hash(s.as_bytes()).to_hex()[..16].to_owned()
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Snapshot {
+    // Directory of the snapshot, relative to the archive.
+    dir: String,
+    // Names of the page and its assets in `dir`, only these are published.
+    files: BTreeSet<String>,
+    // RFC 3339 timestamp.
+    archived_at: String,
+}
+
+/// Local copies of links marked with `!`, so that they can still be read once the original is
+/// gone. Snapshots are kept in the blog directory, next to the content.
+pub struct Archive {
+    root: PathBuf,
+    manifest: Mutex<BTreeMap<String, Snapshot>>,
+    client: reqwest::blocking::Client,
+}
+
+impl Archive {
+    pub fn open(blog_path: &Path) -> Result<Archive> {
+        let root = blog_path.join(ARCHIVE_DIR);
+        let manifest = match std::fs::read_to_string(root.join(MANIFEST)) {
+            Ok(s) => serde_json::from_str(&s)?,
+            Err(_) => BTreeMap::new(),
+        };
+
+        Ok(Archive {
+            root,
+            manifest: Mutex::new(manifest),
+            client: reqwest::blocking::Client::builder()
+                .timeout(Duration::from_secs(30))
+                .user_agent(concat!("kaihan/", env!("CARGO_PKG_VERSION")))
+                .build()?,
+        })
+    }
+
+    /// Whether there is a snapshot of `url`.
+    pub fn contains(&self, url: &str) -> bool {
+        self.manifest.lock().unwrap().contains_key(url)
+    }
+
+    /// Fetches `url` with all its images and stylesheets, unless it was archived before.
+    pub fn snapshot(&self, url: &str) -> Result<()> {
+        if self.manifest.lock().unwrap().contains_key(url) {
+            return Ok(());
+        }
+
+        let page = Url::parse(url)?;
+        let (html, content_type) = self.fetch(&page)?;
+        if content_type != "text/html" {
+            bail!("only HTML pages can be archived, got {content_type:}");
+        }
+
+        let id = short_hash(url);
+        let dir = self.root.join(&id);
+        info!("archiving {url:} into {dir:?}");
+        std::fs::create_dir_all(&dir)?;
+        let files = RefCell::new(BTreeSet::new());
+        let html = self.localize(&page, &String::from_utf8_lossy(&html), &dir, &files)?;
+        std::fs::write(dir.join("index.html"), html)?;
+        let mut files = files.into_inner();
+        files.insert("index.html".to_owned());
+
+        self.manifest.lock().unwrap().insert(
+            url.to_owned(),
+            Snapshot {
+                dir: id,
+                files,
+                archived_at: chrono::Utc::now().to_rfc3339(),
+            },
+        );
+        Ok(())
+    }
+
+    /// Saves the manifest, removes directories of snapshots that aren't in it (like failed
+    /// ones), and returns all snapshots, to be published with the rest of the site.
+    pub fn finish(self, diagnostics: &Diagnostics) -> Result<Vec<RawFile>> {
+        let manifest = self.manifest.into_inner().unwrap();
+        let dirs: BTreeSet<&str> = manifest.values().map(|s| s.dir.as_str()).collect();
+        if self.root.is_dir() {
+            for entry in std::fs::read_dir(&self.root)? {
+                let path = entry?.path();
+                let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+                if path.is_dir() && !dirs.contains(name) {
+                    info!("removing {path:?}, it isn't in the archive manifest");
+                    std::fs::remove_dir_all(&path)?;
+                }
+            }
+        }
+        if manifest.is_empty() {
+            return Ok(vec![]);
+        }
+        let manifest_path = self.root.join(MANIFEST);
+        std::fs::write(&manifest_path, serde_json::to_string_pretty(&manifest)?)?;
+
+        let mut files = Vec::new();
+        for snapshot in manifest.values() {
+            for name in snapshot.files.iter() {
+                let path = Path::new(&snapshot.dir).join(name);
+                match std::fs::read(self.root.join(&path)) {
+                    Ok(data) => files.push(RawFile::Static(StaticContent {
+                        path: Path::new(ARCHIVE_DIR).join(path),
+                        data,
+                    })),
+                    Err(e) => diagnostics.error(
+                        &manifest_path,
+                        None,
+                        format!("failed to read snapshot file {path:?}: {e:}"),
+                    ),
+                }
+            }
+        }
+        Ok(files)
+    }
+
+    /// Downloads `url`, returning the data and its content type.
+    fn fetch(&self, url: &Url) -> Result<(Vec<u8>, String)> {
+        let response = self.client.get(url.as_str()).send()?.error_for_status()?;
+        // Without parameters like the charset.
+        let content_type = response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|t| t.to_str().ok())
+            .and_then(|t| t.split(';').next())
+            .unwrap_or("text/plain")
+            .trim()
+            .to_ascii_lowercase();
+        if response.content_length().is_some_and(|n| n > MAX_SIZE) {
+            bail!("larger than {:} MB", MAX_SIZE >> 20);
+        }
+        // The length isn't always known up front, so also stop reading once past the limit.
+        let mut data = Vec::new();
+        response.take(MAX_SIZE + 1).read_to_end(&mut data)?;
+        if data.len() as u64 > MAX_SIZE {
+            bail!("larger than {:} MB", MAX_SIZE >> 20);
+        }
+        Ok((data, content_type))
+    }
+
+    /// Rewrites a fetched page to be self-contained: Images (including `srcset`s), stylesheets
+    /// and everything they refer to with `url()` are stored next to it in `dir` and added to
+    /// `files`, all other URLs point at the original site, and scripts are dropped.
+    fn localize(
+        &self,
+        page: &Url,
+        html: &str,
+        dir: &Path,
+        files: &RefCell<BTreeSet<String>>,
+    ) -> Result<String> {
+        let assets = Assets {
+            archive: self,
+            dir,
+            files,
+        };
+        let absolute = |el: &mut Element, attr: &str| -> HandlerResult {
+            if let Some(url) = el.get_attribute(attr).and_then(|v| page.join(&v).ok()) {
+                el.set_attribute(attr, url.as_str())?;
+            }
+            Ok(())
+        };
+        // Only called once `absolute` ran, assets that can't be fetched keep pointing there.
+        let asset = |el: &mut Element, attr: &str| -> HandlerResult {
+            if let Some(url) = el.get_attribute(attr).and_then(|v| Url::parse(&v).ok()) {
+                el.set_attribute(attr, &assets.store(&url))?;
+            }
+            Ok(())
+        };
+        // Contents of the `<style>` element currently being read, which can come in pieces.
+        let style = RefCell::new(String::new());
+
+        let html = lol_html::rewrite_str(
+            html,
+            RewriteStrSettings {
+                element_content_handlers: vec![
+                    // Snapshots are static, and shouldn't load anything from elsewhere.
+                    element!("script, iframe, base", |el| {
+                        el.remove();
+                        Ok(())
+                    }),
+                    element!("[href]", |el| absolute(el, "href")),
+                    element!("[src]", |el| absolute(el, "src")),
+                    element!("img[src]", |el| asset(el, "src")),
+                    element!("img[srcset], source[srcset]", |el| {
+                        let srcset = el.get_attribute("srcset").unwrap_or_default();
+                        el.set_attribute("srcset", &assets.srcset(&srcset, page))?;
+                        Ok(())
+                    }),
+                    element!("link[rel~=stylesheet][href]", |el| asset(el, "href")),
+                    element!("[style]", |el| {
+                        // Attributes come as written, quotes in URLs are often escaped.
+                        let css = el.get_attribute("style").unwrap_or_default();
+                        let css = css.replace("&quot;", "\"").replace("&#39;", "'");
+                        el.set_attribute("style", &assets.css(&css, page))?;
+                        Ok(())
+                    }),
+                    text!("style", |t| {
+                        style.borrow_mut().push_str(t.as_str());
+                        if t.last_in_text_node() {
+                            let css = assets.css(&style.take(), page);
+                            t.replace(&css, ContentType::Html);
+                        } else {
+                            t.remove();
+                        }
+                        Ok(())
+                    }),
+                ],
+                ..RewriteStrSettings::new()
+            },
+        )?;
+        Ok(html)
+    }
+}
+
+/// Stores the assets of a snapshot next to its page.
+struct Assets<'a> {
+    archive: &'a Archive,
+    dir: &'a Path,
+    // Names of all assets stored so far.
+    files: &'a RefCell<BTreeSet<String>>,
+}
+
+impl Assets<'_> {
+    /// Stores the asset at `url`, and returns the URL to refer to it by from the snapshot: its
+    /// name, or the original URL if it can't be fetched.
+    fn store(&self, url: &Url) -> String {
+        if !matches!(url.scheme(), "http" | "https") {
+            return url.to_string();
+        }
+        let name = asset_name(url);
+        // Added before fetching, so that stylesheets importing each other are fetched once.
+        if !self.files.borrow_mut().insert(name.clone()) {
+            return name;
+        }
+        let stored = self.archive.fetch(url).and_then(|(data, content_type)| {
+            let data = match content_type.as_str() {
+                "text/css" => self.css(&String::from_utf8_lossy(&data), url).into_bytes(),
+                _ => data,
+            };
+            Ok(std::fs::write(self.dir.join(&name), data)?)
+        });
+        match stored {
+            Ok(()) => name,
+            Err(e) => {
+                warn!("not archiving {url:}: {e:#}");
+                self.files.borrow_mut().remove(&name);
+                url.to_string()
+            }
+        }
+    }
+
+    /// Stores everything the stylesheet `css` at `base` refers to.
+    fn css(&self, css: &str, base: &Url) -> String {
+        css_urls(css, |url| match base.join(url) {
+            // Fragments refer to elements of the page, like SVG filters.
+            Ok(absolute) if !url.starts_with('#') => self.store(&absolute),
+            _ => url.to_owned(),
+        })
+    }
+
+    /// Stores every image of the `srcset` of an element on the page at `base`.
+    fn srcset(&self, srcset: &str, base: &Url) -> String {
+        srcset
+            .split(',')
+            .map(|candidate| {
+                let candidate = candidate.trim();
+                let (url, descriptor) = candidate.split_once(' ').unwrap_or((candidate, ""));
+                let url = match base.join(url) {
+                    Ok(url) => self.store(&url),
+                    Err(_) => url.to_owned(),
+                };
+                format!("{:} {:}", url, descriptor.trim())
+                    .trim_end()
+                    .to_owned()
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// Replaces every URL in `css`, in `url(...)` and `@import "..."`, by what `replace` returns
+/// for it.
+fn css_urls(css: &str, mut replace: impl FnMut(&str) -> String) -> String {
+    let mut out = String::with_capacity(css.len());
+    let mut rest = css;
+    loop {
+        let url_start = rest.find("url(").map(|i| (i, i + 4));
+        let import_start = rest.find("@import").map(|i| (i, i + 7));
+        let Some((start, after)) = [url_start, import_start].into_iter().flatten().min() else {
+            break;
+        };
+        out.push_str(&rest[..after]);
+        rest = &rest[after..];
+
+        let whitespace = rest.len() - rest.trim_start().len();
+        out.push_str(&rest[..whitespace]);
+        rest = &rest[whitespace..];
+        let is_url = after - start == 4;
+        let (url, end) = match rest.chars().next() {
+            Some(quote @ ('"' | '\'')) => match rest[1..].find(quote) {
+                Some(i) => (&rest[1..i + 1], i + 2),
+                None => break,
+            },
+            // `@import url(...)` is left to the next round.
+            _ if !is_url => continue,
+            _ => match rest.find(')') {
+                Some(i) => (rest[..i].trim_end(), i),
+                None => break,
+            },
+        };
+        if url.is_empty() || url.starts_with("data:") {
+            out.push_str(&rest[..end]);
+        } else {
+            write!(out, "\"{:}\"", replace(url).replace('"', "%22")).unwrap();
+        }
+        rest = &rest[end..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// File name for an asset, keeping the extension so that it's served with the right type.
+fn asset_name(url: &Url) -> String {
+    let ext = Path::new(url.path())
+        .extension()
+        .and_then(|e| e.to_str())
+        .filter(|e| e.len() <= 5 && e.chars().all(|c| c.is_ascii_alphanumeric()));
+    match ext {
+        Some(ext) => format!("{:}.{:}", short_hash(url.as_str()), ext),
+        None => short_hash(url.as_str()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Serves `app` on a thread of its own, since fetching blocks.
+    fn serve(app: axum::Router) -> String {
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+            runtime.block_on(async move {
+                let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+                tx.send(listener.local_addr().unwrap()).unwrap();
+                axum::serve(listener, app).await.unwrap();
+            });
+        });
+        format!("http://{:}", rx.recv().unwrap())
+    }
+
+    #[test]
+    fn only_snapshots_in_the_manifest_are_published() {
+        let blog = tempfile::tempdir().unwrap();
+        let root = blog.path().join(ARCHIVE_DIR);
+        for file in [
+            "kept/index.html",
+            "kept/notes.md",
+            "kept/leftover.png",
+            "failed/a.png",
+        ] {
+            std::fs::create_dir_all(root.join(file).parent().unwrap()).unwrap();
+            std::fs::write(root.join(file), file).unwrap();
+        }
+        std::fs::write(
+            root.join(MANIFEST),
+            r#"{"https://example.com/": {"dir": "kept", "files": ["index.html", "notes.md"],
+                "archived_at": "2024-01-02T00:00:00Z"}}"#,
+        )
+        .unwrap();
+
+        let diagnostics = Diagnostics::default();
+        let files = Archive::open(blog.path())
+            .unwrap()
+            .finish(&diagnostics)
+            .unwrap();
+        let published: Vec<_> = files
+            .iter()
+            .map(|f| match f {
+                RawFile::Static(s) => (s.path.to_str().unwrap(), s.data.as_slice()),
+                RawFile::Content(_) => panic!("snapshots aren't content"),
+            })
+            .collect();
+        assert_eq!(
+            published,
+            [
+                ("archive/kept/index.html", &b"kept/index.html"[..]),
+                ("archive/kept/notes.md", b"kept/notes.md"),
+            ]
+        );
+        assert!(!root.join("failed").exists());
+        assert!(root.join("kept/leftover.png").exists());
+    }
+
+    #[test]
+    fn large_responses_are_rejected() {
+        let large = || vec![b'x'; MAX_SIZE as usize + 1];
+        let base = serve(
+            axum::Router::new()
+                .route("/small", axum::routing::get(|| async { "small" }))
+                .route("/large", axum::routing::get(move || async move { large() }))
+                // Without a `Content-Length`.
+                .route(
+                    "/large-streamed",
+                    axum::routing::get(move || async move {
+                        let chunks = large()
+                            .chunks(1 << 20)
+                            .map(|c| Ok::<_, std::io::Error>(c.to_vec()))
+                            .collect::<Vec<_>>();
+                        axum::body::Body::from_stream(futures::stream::iter(chunks))
+                    }),
+                ),
+        );
+        let blog = tempfile::tempdir().unwrap();
+        let archive = Archive::open(blog.path()).unwrap();
+        let fetch = |path| archive.fetch(&Url::parse(&format!("{base:}{path:}")).unwrap());
+
+        let (data, content_type) = fetch("/small").unwrap();
+        assert_eq!(
+            (data.as_slice(), content_type.as_str()),
+            (&b"small"[..], "text/plain")
+        );
+        for path in ["/large", "/large-streamed"] {
+            let e = fetch(path).unwrap_err();
+            assert_eq!(format!("{e:}"), "larger than 20 MB", "{path}");
+        }
+    }
+
+    #[test]
+    fn snapshots_include_assets_referenced_from_css_and_srcset() {
+        let file = |content_type: &'static str, body: &'static str| {
+            axum::routing::get(move || async move {
+                ([(axum::http::header::CONTENT_TYPE, content_type)], body)
+            })
+        };
+        let base = serve(
+            axum::Router::new()
+                .route(
+                    "/post/",
+                    file(
+                        "text/html",
+                        r#"<link rel="stylesheet" href="../site.css">
+<style>h1 { background: url(/bg.png) }</style>
+<img src="a.png" srcset="a.png 1x, /b.png 2x, /missing.png 3x">
+<p style="background: url('data:image/png;base64,AA'), url(&quot;a.png&quot;)">Hi</p>"#,
+                    ),
+                )
+                .route(
+                    "/site.css",
+                    file(
+                        "text/css",
+                        "@import 'more.css';\n@font-face { src: url( \"font.woff2\" ) }",
+                    ),
+                )
+                .route("/more.css", file("text/css", "@import url(site.css);"))
+                .route("/font.woff2", file("font/woff2", "font"))
+                .route("/bg.png", file("image/png", "bg"))
+                .route("/post/a.png", file("image/png", "a"))
+                .route("/b.png", file("image/png", "b")),
+        );
+        let blog = tempfile::tempdir().unwrap();
+        let archive = Archive::open(blog.path()).unwrap();
+        let url = format!("{base:}/post/");
+        archive.snapshot(&url).unwrap();
+
+        let name = |path: &str| asset_name(&Url::parse(&format!("{base:}{path:}")).unwrap());
+        let dir = blog.path().join(ARCHIVE_DIR).join(short_hash(&url));
+        let read = |name: &str| std::fs::read_to_string(dir.join(name)).unwrap();
+        let html = read("index.html");
+        for expected in [
+            format!(r#"<link rel="stylesheet" href="{:}">"#, name("/site.css")),
+            format!(r#"h1 {{ background: url("{:}") }}"#, name("/bg.png")),
+            format!(
+                r#"srcset="{a:} 1x, {b:} 2x, {base:}/missing.png 3x""#,
+                a = name("/post/a.png"),
+                b = name("/b.png")
+            ),
+            format!(
+                r#"style="background: url('data:image/png;base64,AA'), url(&quot;{:}&quot;)""#,
+                name("/post/a.png")
+            ),
+        ] {
+            assert!(html.contains(&expected), "{expected}\n{html}");
+        }
+        assert_eq!(
+            read(&name("/site.css")),
+            format!(
+                "@import \"{:}\";\n@font-face {{ src: url( \"{:}\" ) }}",
+                name("/more.css"),
+                name("/font.woff2")
+            )
+        );
+        assert_eq!(
+            read(&name("/more.css")),
+            format!("@import url(\"{:}\");", name("/site.css"))
+        );
+
+        let files = &archive.manifest.lock().unwrap()[&url].files;
+        let mut expected: BTreeSet<String> = ["/site.css", "/more.css", "/font.woff2", "/bg.png"]
+            .into_iter()
+            .chain(["/post/a.png", "/b.png"])
+            .map(name)
+            .collect();
+        expected.insert("index.html".to_owned());
+        assert_eq!(files, &expected);
+    }
+}
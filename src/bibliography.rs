@@ -0,0 +1,102 @@
+use crate::highlight::escape_html;
+use anyhow::{anyhow, Result};
+use biblatex::{ChunksExt, DateValue, PermissiveType};
+use std::path::Path;
+
+/// The entries of a `.bib` file, which posts cite with `[@key]`.
+pub struct Bibliography(biblatex::Bibliography);
+
+impl Bibliography {
+    pub fn load(path: &Path) -> Result<Bibliography> {
+        let source = std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("failed to read bibliography {path:?}: {e:}"))?;
+        let bibliography = biblatex::Bibliography::parse(&source).map_err(|e| {
+            let line = source[..e.span.start].matches('\n').count() + 1;
+            anyhow!("{:}:{:}: {:}", path.display(), line, e.kind)
+        })?;
+        Ok(Bibliography(bibliography))
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.0.get(key).is_some()
+    }
+
+    /// The entry for `key` as HTML, like `Authors. Year. Title. Venue.`, linking to its DOI or
+    /// URL if it has one.
+    pub fn format(&self, key: &str) -> String {
+        let Some(entry) = self.0.get(key) else {
+            return escape_html(key);
+        };
+
+        let mut parts = Vec::new();
+        if let Ok(authors) = entry.author() {
+            let names: Vec<String> = authors
+                .iter()
+                .map(|p| {
+                    [&p.given_name, &p.prefix, &p.name, &p.suffix]
+                        .iter()
+                        .filter(|s| !s.is_empty())
+                        .map(|s| s.as_str())
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                })
+                .collect();
+            parts.push(escape_html(&match names.as_slice() {
+                [] => String::new(),
+                [one] => one.clone(),
+                [rest @ .., last] => format!("{:} and {:}", rest.join(", "), last),
+            }));
+        }
+        match entry.date() {
+            Ok(PermissiveType::Typed(date)) => {
+                let year = match date.value {
+                    DateValue::At(d) | DateValue::After(d) | DateValue::Before(d) => d.year,
+                    DateValue::Between(d, _) => d.year,
+                };
+                parts.push(year.to_string());
+            }
+            Ok(PermissiveType::Chunks(chunks)) => {
+                parts.push(escape_html(&chunks.format_verbatim()))
+            }
+            Err(_) => {}
+        }
+        if let Ok(title) = entry.title() {
+            parts.push(format!(
+                "<em>{}</em>",
+                escape_html(&title.format_verbatim())
+            ));
+        }
+        let venue = entry
+            .journal()
+            .or_else(|_| entry.book_title())
+            .map(|v| v.format_verbatim())
+            .or_else(|_| entry.how_published().map(|h| h.format_verbatim()))
+            .or_else(|_| {
+                entry.publisher().map(|p| {
+                    p.iter()
+                        .map(|c| c.format_verbatim())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                })
+            });
+        if let Ok(venue) = venue {
+            parts.push(escape_html(&venue));
+        }
+
+        let mut html: String = parts
+            .iter()
+            .filter(|p| !p.is_empty())
+            .map(|p| format!("{:}. ", p.trim_end_matches('.')))
+            .collect();
+        let link = match (entry.doi(), entry.url()) {
+            (Ok(doi), _) => Some(format!("https://doi.org/{doi:}")),
+            (_, Ok(url)) => Some(url),
+            _ => None,
+        };
+        if let Some(link) = link {
+            let link = escape_html(&link);
+            html.push_str(&format!(r#"<a href="{link:}">{link}</a>"#));
+        }
+        html.trim_end().to_owned()
+    }
+}
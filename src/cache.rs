@@ -0,0 +1,243 @@
+use anyhow::Result;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
+
+const CACHE_FILE: &str = ".kaihan-cache.json";
+
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    // Rendering changes between builds of kaihan, so caches are never shared across them, see
+    // `build_id`.
+    version: String,
+    // Fingerprint of the inputs of every output, keyed by path relative to the render directory.
+    outputs: BTreeMap<PathBuf, String>,
+}
+
+/// Remembers what every output of the previous build was rendered from, so that outputs whose
+/// inputs didn't change are neither rendered nor written again.
+pub struct BuildCache {
+    render_path: PathBuf,
+    previous: BTreeMap<PathBuf, String>,
+    current: Mutex<BTreeMap<PathBuf, String>>,
+    written: AtomicUsize,
+}
+
+impl BuildCache {
+    /// Loads the cache left behind by the previous build into `render_path`. If there is no
+    /// usable cache, or `clean` is set, the render directory is wiped instead.
+    pub fn open(render_path: &Path, clean: bool) -> Result<BuildCache> {
+        let cache_file = render_path.join(CACHE_FILE);
+        let manifest = std::fs::read_to_string(&cache_file)
+            .ok()
+            .and_then(|s| serde_json::from_str::<Manifest>(&s).ok())
+            .filter(|m| !clean && m.version == build_id());
+
+        let previous = match manifest {
+            Some(m) => {
+                // Only a successful build leaves a cache behind, otherwise outputs written by an
+                // aborted build could be mistaken as up to date.
+                std::fs::remove_file(&cache_file)?;
+                m.outputs
+            }
+            None => {
+                info!("no usable build cache in {render_path:?}, rendering everything");
+                let _ = std::fs::remove_dir_all(render_path);
+                BTreeMap::new()
+            }
+        };
+        std::fs::create_dir_all(render_path)?;
+
+        Ok(BuildCache {
+            render_path: render_path.to_path_buf(),
+            previous,
+            current: Mutex::new(BTreeMap::new()),
+            written: AtomicUsize::new(0),
+        })
+    }
+
+    /// Writes the output at `path` (relative to the render directory), unless the previous build
+    /// already wrote it from inputs with the same `fingerprint`. Only calls `render` if needed.
+    pub fn write<D: AsRef<[u8]>>(
+        &self,
+        path: impl AsRef<Path>,
+        fingerprint: &str,
+        render: impl FnOnce() -> Result<D>,
+    ) -> Result<()> {
+        let path = path.as_ref();
+        let dst = self.render_path.join(path);
+        if !(self.previous.get(path).is_some_and(|f| f == fingerprint) && dst.exists()) {
+            let data = render()?;
+            std::fs::create_dir_all(dst.parent().unwrap())?;
+            std::fs::write(dst, data)?;
+            self.written.fetch_add(1, Ordering::Relaxed);
+        }
+
+        // Only recorded once written, failed outputs need to be rendered again next time.
+        self.current
+            .lock()
+            .unwrap()
+            .insert(path.to_path_buf(), fingerprint.to_owned());
+        Ok(())
+    }
+
+    /// Removes outputs of the previous build that are no longer produced, and saves the cache
+    /// for the next build.
+    pub fn finish(self) -> Result<()> {
+        let current = self.current.into_inner().unwrap();
+        for stale in self.previous.keys().filter(|p| !current.contains_key(*p)) {
+            info!("removing stale output {stale:?}");
+            let _ = std::fs::remove_file(self.render_path.join(stale));
+        }
+        println!(
+            "{:} of {:} outputs changed",
+            self.written.into_inner(),
+            current.len()
+        );
+
+        let manifest = Manifest {
+            version: build_id().to_owned(),
+            outputs: current,
+        };
+        std::fs::write(
+            self.render_path.join(CACHE_FILE),
+            serde_json::to_string(&manifest)?,
+        )?;
+
+        Ok(())
+    }
+}
+
+/// Identifies the kaihan binary doing the build: its version and a hash of the executable. The
+/// version alone isn't enough, it stays the same while rendering keeps changing.
+fn build_id() -> &'static str {
+    static BUILD_ID: OnceLock<String> = OnceLock::new();
+    BUILD_ID.get_or_init(|| {
+        match std::env::current_exe().and_then(std::fs::read) {
+            Ok(exe) => format!(
+                "{:} {:}",
+                env!("CARGO_PKG_VERSION"),
+                blake3::hash(&exe).to_hex()
+            ),
+            Err(e) => {
+                // Never matches a previous build, so everything is rendered again.
+                warn!("failed to read the kaihan executable, not reusing the build cache: {e:}");
+                format!(
+                    "{:} unknown {:}",
+                    env!("CARGO_PKG_VERSION"),
+                    chrono::Utc::now().timestamp_nanos_opt().unwrap_or_default()
+                )
+            }
+        }
+    })
+}
+
+/// Hash over everything an output is derived from.
+#[derive(Default)]
+pub struct Fingerprint(blake3::Hasher);
+
+impl Fingerprint {
+    pub fn add(mut self, data: impl AsRef<[u8]>) -> Fingerprint {
+        let data = data.as_ref();
+        // Length prefix, so that ("ab", "c") and ("a", "bc") hash differently.
+        self.0.update(&(data.len() as u64).to_le_bytes());
+        self.0.update(data);
+        self
+    }
+
+    pub fn add_serialized(self, value: &impl Serialize) -> Result<Fingerprint> {
+        Ok(self.add(serde_json::to_vec(value)?))
+    }
+
+    pub fn finish(&self) -> String {
+        self.0.finalize().to_hex().to_string()
+    }
+}
+
+/// Fingerprints every template together with all templates it extends, includes or imports.
+pub fn template_fingerprints(jinja: &minijinja::Environment) -> HashMap<String, String> {
+    let sources: BTreeMap<&str, String> = jinja
+        .templates()
+        .map(|(name, tmpl)| (name, tmpl.source().to_owned()))
+        .collect();
+
+    sources
+        .keys()
+        .map(|name| {
+            let mut used = BTreeSet::new();
+            let mut todo = vec![*name];
+            while let Some(n) = todo.pop() {
+                if !used.insert(n) {
+                    continue;
+                }
+                match sources.get(n).map(|s| template_dependencies(s)) {
+                    Some(Some(deps)) => todo.extend(deps),
+                    // Names computed at render time could refer to anything.
+                    Some(None) => used.extend(sources.keys().copied()),
+                    // Missing templates will fail rendering (or be ignored) either way.
+                    None => {}
+                }
+            }
+
+            let fingerprint = used
+                .iter()
+                .filter_map(|n| Some((n, sources.get(n)?)))
+                .fold(Fingerprint::default(), |f, (n, s)| f.add(n).add(s));
+            (name.to_string(), fingerprint.finish())
+        })
+        .collect()
+}
+
+/// Names of the templates referenced by `source`, or `None` if some of them are only known at
+/// render time.
+fn template_dependencies(source: &str) -> Option<Vec<&str>> {
+    let mut deps = vec![];
+    for tag in source.split("{%").skip(1) {
+        let tag = tag.split("%}").next().unwrap_or("");
+        let tag = tag.trim_matches(|c: char| c == '-' || c == '+' || c.is_whitespace());
+        let keyword = tag.split_whitespace().next().unwrap_or("");
+        if !["extends", "include", "import", "from"].contains(&keyword) {
+            continue;
+        }
+
+        let names: Vec<&str> = tag.split(['"', '\'']).skip(1).step_by(2).collect();
+        if names.is_empty() {
+            return None;
+        }
+        deps.extend(names);
+    }
+    Some(deps)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failed_outputs_are_rendered_again() {
+        let out = tempfile::tempdir().unwrap();
+        let cache = BuildCache::open(out.path(), false).unwrap();
+        assert!(cache
+            .write::<String>("a.html", "1", || anyhow::bail!("broken"))
+            .is_err());
+        cache.write("b.html", "1", || Ok("b")).unwrap();
+        cache.finish().unwrap();
+
+        let cache = BuildCache::open(out.path(), false).unwrap();
+        let rendered = |path| {
+            let mut rendered = false;
+            cache
+                .write(path, "1", || {
+                    rendered = true;
+                    Ok("")
+                })
+                .unwrap();
+            rendered
+        };
+        assert!(rendered("a.html"));
+        assert!(!rendered("b.html"));
+    }
+}
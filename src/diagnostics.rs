@@ -0,0 +1,104 @@
+use anyhow::{bail, Result};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// A problem with a specific source file, ideally pointing at the offending line.
+#[derive(Debug)]
+pub struct Diagnostic {
+    pub path: PathBuf,
+    // Starting at 1, `None` if the problem is with the file as a whole.
+    pub line: Option<usize>,
+    pub severity: Severity,
+    pub message: String,
+}
+
+/// Only errors fail the build, warnings are merely printed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "{:}:{:}: ", self.path.display(), line)?,
+            None => write!(f, "{:}: ", self.path.display())?,
+        }
+        match self.severity {
+            Severity::Error => write!(f, "error: {:}", self.message),
+            Severity::Warning => write!(f, "warning: {:}", self.message),
+        }
+    }
+}
+
+/// Collects problems with the content over the whole build, instead of stopping at the first
+/// one, so that they can all be fixed in one go.
+#[derive(Default)]
+pub struct Diagnostics {
+    entries: Mutex<Vec<Diagnostic>>,
+}
+
+impl Diagnostics {
+    pub fn error(&self, path: impl AsRef<Path>, line: Option<usize>, message: impl Into<String>) {
+        self.push(path, line, Severity::Error, message)
+    }
+
+    pub fn warning(&self, path: impl AsRef<Path>, line: Option<usize>, message: impl Into<String>) {
+        self.push(path, line, Severity::Warning, message)
+    }
+
+    fn push(
+        &self,
+        path: impl AsRef<Path>,
+        line: Option<usize>,
+        severity: Severity,
+        message: impl Into<String>,
+    ) {
+        self.entries.lock().unwrap().push(Diagnostic {
+            path: path.as_ref().to_path_buf(),
+            line,
+            severity,
+            message: message.into(),
+        });
+    }
+
+    /// Records a failed template render. Points at the template if the error says where in it
+    /// things went wrong, otherwise at the `source` being rendered.
+    pub fn template_error(&self, templates_path: &Path, source: &Path, e: &anyhow::Error) {
+        match e.downcast_ref::<minijinja::Error>() {
+            Some(err) if err.name().is_some() && err.line().is_some() => {
+                let message = match err.detail() {
+                    Some(detail) => format!("{:}: {:}", err.kind(), detail),
+                    None => err.kind().to_string(),
+                };
+                self.error(
+                    templates_path.join(err.name().unwrap()),
+                    err.line(),
+                    format!("{:} (while rendering {:})", message, source.display()),
+                )
+            }
+            _ => self.error(source, None, format!("{e:#}")),
+        }
+    }
+
+    /// Prints everything that was found, ordered by file and line, and fails if there were any
+    /// errors.
+    pub fn report(self) -> Result<()> {
+        let mut entries = self.entries.into_inner().unwrap();
+        entries.sort_by(|a, b| (&a.path, a.line).cmp(&(&b.path, b.line)));
+        for d in entries.iter() {
+            eprintln!("{d:}");
+        }
+
+        let errors = entries
+            .iter()
+            .filter(|d| d.severity == Severity::Error)
+            .count();
+        if errors > 0 {
+            bail!("found {:} errors in the content", errors);
+        }
+        Ok(())
+    }
+}
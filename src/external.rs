@@ -0,0 +1,301 @@
+use crate::diagnostics::Diagnostics;
+use crate::{read_source_files, Config, RawFile};
+use anyhow::Result;
+use futures::StreamExt;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Results of previous checks, stored in the blog directory next to the content.
+const CACHE_FILE: &str = ".kaihan-external-links.json";
+
+/// How many hosts are checked at the same time.
+const MAX_HOSTS: usize = 16;
+
+/// How gently to check external links.
+pub struct Limits {
+    // Requests in flight to the same host.
+    pub per_host: usize,
+    pub timeout: Duration,
+    // How long results are reused before checking a URL again.
+    pub ttl: Duration,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "status", rename_all = "snake_case")]
+enum Status {
+    Ok,
+    Redirected { location: String },
+    Dead { reason: String },
+    // The request itself failed, which is likely temporary and never cached.
+    Unreachable { reason: String },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Checked {
+    // Unix timestamp in seconds.
+    checked_at: i64,
+    #[serde(flatten)]
+    status: Status,
+}
+
+/// Requests every external URL linked from the content, and reports the ones that are dead or
+/// redirect elsewhere, per post. Results are cached for `limits.ttl`.
+pub async fn check(blog_path: &Path, config: &Config, limits: &Limits) -> Result<()> {
+    let diagnostics = Diagnostics::default();
+    let files = read_source_files(
+        &blog_path.join(&config.content_path),
+        Path::new(""),
+        &diagnostics,
+    )?;
+
+    // Every external link as (source, line, url).
+    let mut links: Vec<(PathBuf, usize, String)> = Vec::new();
+    for f in files.iter() {
+        let RawFile::Content(c) = f else {
+            continue;
+        };
+        match c.article() {
+            Ok(a) => links.extend(
+                a.links
+                    .iter()
+                    .filter(|l| is_external(&l.url, &config.siteurl))
+                    .map(|l| (c.source.clone(), c.body_line + l.line - 1, l.url.clone())),
+            ),
+            Err(e) => diagnostics.error(&c.source, None, format!("{e:#}")),
+        }
+    }
+
+    let cache_path = blog_path.join(CACHE_FILE);
+    let mut cache: BTreeMap<String, Checked> = std::fs::read_to_string(&cache_path)
+        .ok()
+        .and_then(|s| serde_json::from_str(&s).ok())
+        .unwrap_or_default();
+    let urls: BTreeSet<&str> = links.iter().map(|(_, _, url)| url.as_str()).collect();
+    let unreachable = check_urls(&urls, &mut cache, limits, chrono::Utc::now().timestamp()).await?;
+    cache.retain(|url, _| urls.contains(url.as_str()));
+    std::fs::write(&cache_path, serde_json::to_string_pretty(&cache)?)?;
+
+    for (source, line, url) in links.iter() {
+        if let Some(reason) = unreachable.get(url.as_str()) {
+            diagnostics.error(
+                source,
+                Some(*line),
+                format!("unreachable link {url:}, {reason:}"),
+            );
+            continue;
+        }
+        match &cache[url].status {
+            Status::Ok => {}
+            Status::Redirected { location } => diagnostics.warning(
+                source,
+                Some(*line),
+                format!("{url:} redirects to {location:}"),
+            ),
+            Status::Dead { reason } | Status::Unreachable { reason } => {
+                diagnostics.error(source, Some(*line), format!("dead link {url:}, {reason:}"))
+            }
+        }
+    }
+
+    diagnostics.report()
+}
+
+/// Requests every URL in `urls` that isn't in `cache` yet, or was checked more than `limits.ttl`
+/// before `now` (a Unix timestamp in seconds), and records the results in `cache`. Unreachable
+/// URLs aren't cached, they are returned with the reason instead.
+async fn check_urls<'u>(
+    urls: &BTreeSet<&'u str>,
+    cache: &mut BTreeMap<String, Checked>,
+    limits: &Limits,
+    now: i64,
+) -> Result<BTreeMap<&'u str, String>> {
+    let ttl = limits.ttl.as_secs() as i64;
+    let mut by_host: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    for url in urls.iter() {
+        if cache.get(*url).is_none_or(|c| now - c.checked_at >= ttl) {
+            by_host.entry(host(url)).or_default().push(*url);
+        }
+    }
+    let todo: usize = by_host.values().map(|u| u.len()).sum();
+    println!(
+        "checking {:} external URLs, {:} more are cached",
+        todo,
+        urls.len() - todo
+    );
+
+    let client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .timeout(limits.timeout)
+        .user_agent(concat!("kaihan/", env!("CARGO_PKG_VERSION")))
+        .build()?;
+    let mut unreachable = BTreeMap::new();
+    let results: Vec<(&'u str, Status)> = futures::stream::iter(by_host.into_values())
+        .map(|urls| {
+            let client = &client;
+            futures::stream::iter(urls)
+                .map(move |url| async move {
+                    let status = check_url(client, url).await;
+                    info!("{url:}: {status:?}");
+                    (url, status)
+                })
+                .buffer_unordered(limits.per_host)
+        })
+        .flatten_unordered(MAX_HOSTS)
+        .collect()
+        .await;
+
+    for (url, status) in results {
+        if let Status::Unreachable { reason } = status {
+            unreachable.insert(url, reason);
+            continue;
+        }
+        cache.insert(
+            url.to_owned(),
+            Checked {
+                checked_at: now,
+                status,
+            },
+        );
+    }
+    Ok(unreachable)
+}
+
+fn is_external(url: &str, siteurl: &str) -> bool {
+    (url.starts_with("http://") || url.starts_with("https://")) && !url.starts_with(siteurl)
+}
+
+fn host(url: &str) -> &str {
+    let rest = url.split_once("://").map_or(url, |(_, r)| r);
+    rest.split(['/', '?', '#']).next().unwrap()
+}
+
+async fn check_url(client: &reqwest::Client, url: &str) -> Status {
+    let response = match client.head(url).send().await {
+        // Plenty of servers don't handle HEAD requests properly, give them another chance.
+        Ok(r) if r.status().is_client_error() || r.status().is_server_error() => {
+            client.get(url).send().await
+        }
+        r => r,
+    };
+
+    match response {
+        Ok(r) if r.status().is_success() => Status::Ok,
+        Ok(r) if r.status().is_redirection() => {
+            let location = r
+                .headers()
+                .get(reqwest::header::LOCATION)
+                .and_then(|l| l.to_str().ok())
+                .and_then(|l| r.url().join(l).ok())
+                .map_or_else(|| "nowhere".to_owned(), |l| l.to_string());
+            Status::Redirected { location }
+        }
+        Ok(r) => Status::Dead {
+            reason: r.status().to_string(),
+        },
+        Err(e) if e.is_timeout() => Status::Unreachable {
+            reason: "timed out".to_owned(),
+        },
+        Err(e) => {
+            // The outermost errors only repeat the URL, the innermost one says what went wrong.
+            let mut cause: &dyn std::error::Error = &e;
+            while let Some(source) = cause.source() {
+                cause = source;
+            }
+            Status::Unreachable {
+                reason: cause.to_string(),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::{header, StatusCode};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// Serves `/ok`, `/moved` (to `/ok`) and everything else as not found, counting requests.
+    async fn serve(requests: Arc<AtomicUsize>) -> String {
+        let count = axum::middleware::from_fn(move |request, next: axum::middleware::Next| {
+            requests.fetch_add(1, Ordering::SeqCst);
+            next.run(request)
+        });
+        let app = axum::Router::new()
+            .route("/ok", axum::routing::get(|| async { "ok" }))
+            .route(
+                "/moved",
+                axum::routing::get(|| async {
+                    (StatusCode::MOVED_PERMANENTLY, [(header::LOCATION, "/ok")])
+                }),
+            )
+            .layer(count);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{addr:}")
+    }
+
+    #[tokio::test]
+    async fn check_urls_against_local_server() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let base = serve(requests.clone()).await;
+        let (ok, missing, moved) = (
+            format!("{base:}/ok"),
+            format!("{base:}/missing"),
+            format!("{base:}/moved"),
+        );
+        let urls = BTreeSet::from([ok.as_str(), missing.as_str(), moved.as_str()]);
+        let limits = Limits {
+            per_host: 1,
+            timeout: Duration::from_secs(5),
+            ttl: Duration::from_secs(100),
+        };
+
+        let mut cache = BTreeMap::new();
+        let unreachable = check_urls(&urls, &mut cache, &limits, 1000).await.unwrap();
+        assert!(unreachable.is_empty(), "{unreachable:?}");
+        assert!(matches!(cache[&ok].status, Status::Ok));
+        assert!(
+            matches!(&cache[&missing].status, Status::Dead { reason } if reason == "404 Not Found")
+        );
+        assert!(
+            matches!(&cache[&moved].status, Status::Redirected { location } if *location == ok)
+        );
+        // HEAD for each, and GET again for the missing one.
+        assert_eq!(requests.load(Ordering::SeqCst), 4);
+
+        // Within the TTL, nothing is requested again.
+        check_urls(&urls, &mut cache, &limits, 1099).await.unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 4);
+
+        // Afterwards, only expired results are.
+        cache.get_mut(&ok).unwrap().checked_at = 1050;
+        check_urls(&urls, &mut cache, &limits, 1100).await.unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 7);
+        assert_eq!(cache[&ok].checked_at, 1050);
+        assert_eq!(cache[&missing].checked_at, 1100);
+    }
+
+    #[tokio::test]
+    async fn unreachable_urls_are_not_cached() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{:}/", listener.local_addr().unwrap());
+        drop(listener);
+
+        let limits = Limits {
+            per_host: 1,
+            timeout: Duration::from_secs(5),
+            ttl: Duration::from_secs(100),
+        };
+        let mut cache = BTreeMap::new();
+        let unreachable = check_urls(&BTreeSet::from([url.as_str()]), &mut cache, &limits, 0)
+            .await
+            .unwrap();
+        assert!(unreachable.contains_key(url.as_str()));
+        assert!(cache.is_empty());
+    }
+}
@@ -0,0 +1,412 @@
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// A problem with the header, `line` is relative to the start of the file, starting at 1.
+#[derive(Debug)]
+pub struct Error {
+    pub line: usize,
+    pub message: String,
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {:}: {:}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for Error {}
+
+pub struct Document<'a> {
+    pub metadata: Metadata,
+    pub body: &'a str,
+    // Line of the file the body starts on.
+    pub body_line: usize,
+}
+
+/// Metadata values by lower case key, remembering the line every key was defined on.
+#[derive(Debug, Default)]
+pub struct Metadata {
+    values: serde_json::Map<String, Value>,
+    lines: HashMap<String, usize>,
+}
+
+impl Metadata {
+    pub fn values(&self) -> &serde_json::Map<String, Value> {
+        &self.values
+    }
+
+    /// Line `key` was defined on, or the first line if it wasn't defined at all.
+    pub fn line(&self, key: &str) -> usize {
+        self.lines.get(key).copied().unwrap_or(1)
+    }
+
+    /// The value of `key` as a string. Numbers and booleans are converted, anything else is an
+    /// error.
+    pub fn string(&self, key: &str) -> Result<Option<String>, Error> {
+        match self.values.get(key) {
+            None => Ok(None),
+            Some(Value::String(s)) => Ok(Some(s.clone())),
+            Some(v @ (Value::Number(_) | Value::Bool(_))) => Ok(Some(v.to_string())),
+            Some(v) => Err(self.error(key, format!("`{key:}` must be a string, got {v:}"))),
+        }
+    }
+
+    /// The value of `key` as a list of strings, either given as a list or as a comma separated
+    /// string.
+    pub fn list(&self, key: &str) -> Result<Vec<String>, Error> {
+        match self.values.get(key) {
+            None => Ok(vec![]),
+            Some(Value::String(s)) => Ok(s
+                .split(",")
+                .map(|t| t.trim().to_owned())
+                .filter(|t| !t.is_empty())
+                .collect()),
+            Some(Value::Array(items)) => items
+                .iter()
+                .map(|v| match v {
+                    Value::String(s) => Ok(s.clone()),
+                    Value::Number(_) | Value::Bool(_) => Ok(v.to_string()),
+                    _ => Err(self.error(key, format!("`{key:}` must only contain strings"))),
+                })
+                .collect(),
+            Some(v) => Err(self.error(key, format!("`{key:}` must be a list, got {v:}"))),
+        }
+    }
+
+    fn error(&self, key: &str, message: String) -> Error {
+        Error {
+            line: self.line(key),
+            message,
+        }
+    }
+
+    fn insert(&mut self, key: &str, value: Value, line: usize) {
+        let key = key.to_ascii_lowercase();
+        self.lines.insert(key.clone(), line);
+        self.values.insert(key, value);
+    }
+}
+
+/// Splits a content file into its metadata header and the markdown body. Expects `\n` line
+/// endings. Three header formats are supported:
+///
+/// Pelican style `Key: value` lines, ended by an empty line. Indented lines continue the value
+/// of the previous key, all values are strings.
+///
+/// YAML between `---` lines, and TOML between `+++` lines, both with typed values.
+pub fn parse(contents: &str) -> Result<Document<'_>, Vec<Error>> {
+    let first_line = contents.lines().next().unwrap_or("").trim_end();
+    match first_line {
+        "---" => parse_delimited(contents, "---", parse_yaml),
+        "+++" => parse_delimited(contents, "+++", parse_toml),
+        _ => parse_pelican(contents),
+    }
+}
+
+fn parse_pelican(contents: &str) -> Result<Document<'_>, Vec<Error>> {
+    let mut metadata = Metadata::default();
+    let mut errors = Vec::new();
+    // Key and value currently being parsed, values may span several lines.
+    let mut current: Option<(String, String, usize)> = None;
+    let mut body_start = contents.len();
+    let mut body_line = contents.lines().count() + 1;
+
+    let mut offset = 0;
+    for (i, l) in contents.split_inclusive('\n').enumerate() {
+        let line = i + 1;
+        offset += l.len();
+        let l = l.trim_end();
+
+        if l.trim().is_empty() {
+            body_start = offset;
+            body_line = line + 1;
+            break;
+        }
+
+        if l.starts_with([' ', '\t']) {
+            if let Some((_, value, _)) = current.as_mut() {
+                value.push('\n');
+                value.push_str(l.trim());
+                continue;
+            }
+        } else if let Some((k, v)) = l.split_once(':') {
+            if is_key(k) {
+                if let Some((k, v, line)) = current.take() {
+                    metadata.insert(&k, Value::String(v), line);
+                }
+                current = Some((k.to_owned(), v.trim().to_owned(), line));
+                continue;
+            }
+        }
+
+        errors.push(Error {
+            line,
+            message: if line == 1 {
+                format!("expected a metadata header starting with `key: value`, `---` or `+++`, got {l:?}")
+            } else {
+                format!("metadata must be `key: value`, got {l:?}")
+            },
+        });
+    }
+    if let Some((k, v, line)) = current.take() {
+        metadata.insert(&k, Value::String(v), line);
+    }
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    Ok(Document {
+        metadata,
+        body: &contents[body_start..],
+        body_line,
+    })
+}
+
+fn is_key(k: &str) -> bool {
+    !k.is_empty()
+        && k.chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// Parses a header enclosed in `delimiter` lines with `parse_block`, which gets the contents
+/// between the delimiters.
+fn parse_delimited<'a>(
+    contents: &'a str,
+    delimiter: &str,
+    parse_block: fn(&str) -> Result<serde_json::Map<String, Value>, Error>,
+) -> Result<Document<'a>, Vec<Error>> {
+    let mut offset = 0;
+    let mut end = None;
+    for (i, l) in contents.split_inclusive('\n').enumerate() {
+        offset += l.len();
+        if i > 0 && l.trim_end() == delimiter {
+            end = Some((offset - l.len(), offset, i + 1));
+            break;
+        }
+    }
+    let Some((block_end, body_start, closing_line)) = end else {
+        return Err(vec![Error {
+            line: 1,
+            message: format!("metadata header is never closed by a `{delimiter:}` line"),
+        }]);
+    };
+
+    let block_start = contents.find('\n').unwrap() + 1;
+    let block = &contents[block_start.min(block_end)..block_end];
+    let values = parse_block(block).map_err(|e| {
+        vec![Error {
+            // The block starts on the second line.
+            line: e.line + 1,
+            message: e.message,
+        }]
+    })?;
+
+    let lines = top_level_key_lines(block);
+    let mut metadata = Metadata::default();
+    for (k, v) in values {
+        let line = lines.get(&k.to_ascii_lowercase()).map_or(1, |l| l + 1);
+        metadata.insert(&k, v, line);
+    }
+
+    Ok(Document {
+        metadata,
+        body: &contents[body_start..],
+        body_line: closing_line + 1,
+    })
+}
+
+fn parse_yaml(block: &str) -> Result<serde_json::Map<String, Value>, Error> {
+    if block.trim().is_empty() {
+        return Ok(serde_json::Map::new());
+    }
+    serde_yaml_ng::from_str(block).map_err(|e| Error {
+        line: e.location().map_or(1, |l| l.line()),
+        // The location is relative to the block, the line is reported separately instead.
+        message: format!(
+            "invalid YAML: {:}",
+            e.to_string().split(" at line ").next().unwrap()
+        ),
+    })
+}
+
+fn parse_toml(block: &str) -> Result<serde_json::Map<String, Value>, Error> {
+    let table: toml::Table = toml::from_str(block).map_err(|e| Error {
+        line: e
+            .span()
+            .map_or(1, |s| block[..s.start].matches('\n').count() + 1),
+        message: format!("invalid TOML: {:}", e.message().trim().replace('\n', ", ")),
+    })?;
+    Ok(table
+        .into_iter()
+        .map(|(k, v)| (k, toml_to_json(v)))
+        .collect())
+}
+
+fn toml_to_json(v: toml::Value) -> Value {
+    match v {
+        toml::Value::String(s) => Value::String(s),
+        toml::Value::Integer(i) => i.into(),
+        toml::Value::Float(f) => serde_json::Number::from_f64(f).map_or(Value::Null, Value::Number),
+        toml::Value::Boolean(b) => b.into(),
+        // Dates are handled like in the other formats, where they are plain strings.
+        toml::Value::Datetime(d) => Value::String(d.to_string()),
+        toml::Value::Array(a) => a.into_iter().map(toml_to_json).collect(),
+        toml::Value::Table(t) => {
+            Value::Object(t.into_iter().map(|(k, v)| (k, toml_to_json(v))).collect())
+        }
+    }
+}
+
+/// Line (relative to `block`, starting at 1) of every unindented `key:` or `key =`, so that
+/// errors about typed values can point at their definition.
+fn top_level_key_lines(block: &str) -> HashMap<String, usize> {
+    let mut lines = HashMap::new();
+    for (i, l) in block.lines().enumerate() {
+        if l.starts_with('[') {
+            // Everything after a TOML table header belongs to that table.
+            break;
+        }
+        if let Some(k) = l.split([':', '=']).next() {
+            let k = k.trim_end().trim_matches(['"', '\'']);
+            if is_key(k) && !l.starts_with([' ', '\t']) {
+                lines.entry(k.to_ascii_lowercase()).or_insert(i + 1);
+            }
+        }
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn errors(contents: &str) -> Vec<(usize, String)> {
+        match parse(contents) {
+            Ok(_) => panic!("expected errors for {contents:?}"),
+            Err(errors) => errors.into_iter().map(|e| (e.line, e.message)).collect(),
+        }
+    }
+
+    #[test]
+    fn pelican() {
+        let d =
+            parse("Title: Hello\nSummary: first\n  second\n\tthird\nTags: a, b\n\nBody\n").unwrap();
+        let m = &d.metadata;
+        assert_eq!(m.string("title").unwrap().as_deref(), Some("Hello"));
+        assert_eq!(
+            m.string("summary").unwrap().as_deref(),
+            Some("first\nsecond\nthird")
+        );
+        assert_eq!(m.list("tags").unwrap(), ["a", "b"]);
+        assert_eq!(m.line("summary"), 2);
+        assert_eq!(m.line("tags"), 5);
+        assert_eq!(d.body, "Body\n");
+        assert_eq!(d.body_line, 7);
+    }
+
+    #[test]
+    fn pelican_without_space() {
+        let d = parse("title:Hello\ndate:2024-01-02 10:30\n\nBody").unwrap();
+        assert_eq!(
+            d.metadata.string("title").unwrap().as_deref(),
+            Some("Hello")
+        );
+        assert_eq!(
+            d.metadata.string("date").unwrap().as_deref(),
+            Some("2024-01-02 10:30")
+        );
+    }
+
+    #[test]
+    fn pelican_errors() {
+        assert_eq!(
+            errors("Title: Hello\nnot metadata\nDate: 2024-01-02\nalso not\n\nBody"),
+            [
+                (
+                    2,
+                    r#"metadata must be `key: value`, got "not metadata""#.to_owned()
+                ),
+                (
+                    4,
+                    r#"metadata must be `key: value`, got "also not""#.to_owned()
+                ),
+            ]
+        );
+        assert_eq!(errors("# Just a post\n")[0].0, 1);
+    }
+
+    #[test]
+    fn yaml() {
+        let d = parse(
+            "---\ntitle: Hello\ndraft: true\nweight: 3\ntags:\n  - a\n  - b\nextra:\n  x: 1.5\n---\nBody\n",
+        )
+        .unwrap();
+        let m = &d.metadata;
+        assert_eq!(m.values()["draft"], json!(true));
+        assert_eq!(m.values()["weight"], json!(3));
+        assert_eq!(m.values()["extra"], json!({"x": 1.5}));
+        assert_eq!(m.list("tags").unwrap(), ["a", "b"]);
+        assert_eq!(m.string("weight").unwrap().as_deref(), Some("3"));
+        assert_eq!(m.line("tags"), 5);
+        assert_eq!(d.body, "Body\n");
+        assert_eq!(d.body_line, 11);
+    }
+
+    #[test]
+    fn toml() {
+        let d = parse(
+            "+++\ntitle = \"Hello\"\ndraft = false\ntags = [\"a\", \"b\"]\ndate = 2024-01-02\n\n[extra]\nx = 1\n+++\nBody\n",
+        )
+        .unwrap();
+        let m = &d.metadata;
+        assert_eq!(m.values()["draft"], json!(false));
+        assert_eq!(m.values()["extra"], json!({"x": 1}));
+        assert_eq!(m.list("tags").unwrap(), ["a", "b"]);
+        assert_eq!(m.string("date").unwrap().as_deref(), Some("2024-01-02"));
+        assert_eq!(m.line("date"), 5);
+        assert_eq!(d.body_line, 10);
+    }
+
+    #[test]
+    fn typed_value_errors() {
+        let d = parse("---\ntitle: Hello\ntags:\n  nested: map\n---\n").unwrap();
+        let e = d.metadata.list("tags").unwrap_err();
+        assert_eq!(e.line, 3);
+        let e = parse("+++\ntitle = [1, 2]\n+++\n")
+            .unwrap()
+            .metadata
+            .string("title")
+            .unwrap_err();
+        assert_eq!(e.line, 2);
+    }
+
+    #[test]
+    fn unclosed() {
+        assert_eq!(
+            errors("---\ntitle: Hello\n\nBody\n"),
+            [(
+                1,
+                "metadata header is never closed by a `---` line".to_owned()
+            )]
+        );
+        assert_eq!(errors("+++\ntitle = \"Hello\"\n")[0].0, 1);
+    }
+
+    #[test]
+    fn syntax_errors() {
+        // The list is only found to be unclosed at the end of the block.
+        assert_eq!(
+            errors("---\ntitle: Hello\ntags: [a, b\n---\nBody\n"),
+            [(
+                4,
+                "invalid YAML: did not find expected ',' or ']'".to_owned()
+            )]
+        );
+
+        let e = errors("+++\ntitle = \"Hello\"\ndraft = yes\n+++\n");
+        assert_eq!(e.len(), 1);
+        assert!(e[0].1.starts_with("invalid TOML: "), "{:}", e[0].1);
+        assert_eq!(e[0].0, 3);
+    }
+}
@@ -0,0 +1,218 @@
+use anyhow::{anyhow, bail, Result};
+use std::sync::LazyLock;
+use syntect::highlighting::ThemeSet;
+use syntect::html::ClassStyle;
+use syntect::parsing::{ParseState, Scope, ScopeStack, SyntaxReference, SyntaxSet};
+use syntect::util::LinesWithEndings;
+
+/// Prefixed, so that highlighting doesn't clash with classes used by the templates.
+const CLASS_STYLE: ClassStyle = ClassStyle::SpacedPrefixed { prefix: "hl-" };
+
+/// Styles for line numbers and highlighted lines, added to every theme.
+const LINES_CSS: &str = r#"
+.code-line.highlighted {
+ background-color: rgba(255, 220, 0, 0.25);
+}
+.line-number {
+ display: inline-block;
+ min-width: 2em;
+ padding-right: 1em;
+ text-align: right;
+ opacity: 0.5;
+ user-select: none;
+}
+"#;
+
+static SYNTAXES: LazyLock<SyntaxSet> = LazyLock::new(SyntaxSet::load_defaults_newlines);
+
+/// The info string of a fenced code block: the language, optionally followed by attributes like
+/// `rust {hl=3-5, linenos, title="src/main.rs"}`.
+#[derive(Debug, Default)]
+pub struct Info {
+    pub lang: String,
+    // Show line numbers.
+    line_numbers: bool,
+    // Ranges of lines to emphasize, starting at 1, inclusive.
+    highlighted: Vec<(usize, usize)>,
+    // Caption, usually the file the code is from.
+    title: Option<String>,
+    // Attributes meant for other tools, like Pandoc's `.numberLines`, which are ignored.
+    pub unknown: Vec<String>,
+}
+
+impl Info {
+    pub fn parse(info: &str) -> Result<Info> {
+        let (lang, attributes) = match info.find('{') {
+            Some(i) => (info[..i].trim(), info[i..].trim()),
+            None => (info.trim(), ""),
+        };
+        let mut parsed = Info {
+            lang: lang.to_owned(),
+            ..Info::default()
+        };
+        if attributes.is_empty() {
+            return Ok(parsed);
+        }
+
+        let Some(attributes) = attributes
+            .strip_prefix('{')
+            .and_then(|a| a.strip_suffix('}'))
+        else {
+            bail!("code block attributes must be enclosed in {{...}}, got {attributes:?}");
+        };
+        for (key, value) in split_attributes(attributes) {
+            match (key.as_str(), value) {
+                ("linenos", None) => parsed.line_numbers = true,
+                ("hl", Some(v)) => {
+                    for range in v.split(',') {
+                        parsed
+                            .highlighted
+                            .push(parse_range(range.trim()).ok_or_else(|| {
+                                anyhow!("invalid line range {range:?} in `hl`, expected e.g. 3-5")
+                            })?);
+                    }
+                }
+                ("title", Some(v)) => parsed.title = Some(v),
+                (_, None) => parsed.unknown.push(key),
+                (_, Some(v)) => parsed.unknown.push(format!("{key:}={v:}")),
+            }
+        }
+        Ok(parsed)
+    }
+
+    /// Whether there is anything to do beyond rendering the code as it is.
+    fn has_attributes(&self) -> bool {
+        self.line_numbers || !self.highlighted.is_empty() || self.title.is_some()
+    }
+}
+
+/// Splits `hl=3-5, linenos, title="a, b"` into keys and values, quotes protect separators.
+fn split_attributes(attributes: &str) -> Vec<(String, Option<String>)> {
+    let mut tokens = vec![];
+    let mut current = String::new();
+    let mut quoted = false;
+    for c in attributes.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            ',' | ' ' | '\t' if !quoted => tokens.push(std::mem::take(&mut current)),
+            _ => current.push(c),
+        }
+    }
+    tokens.push(current);
+
+    tokens
+        .into_iter()
+        .filter(|t| !t.is_empty())
+        .map(|t| match t.split_once('=') {
+            Some((k, v)) => (k.trim().to_owned(), Some(v.trim().to_owned())),
+            None => (t, None),
+        })
+        .collect()
+}
+
+fn parse_range(range: &str) -> Option<(usize, usize)> {
+    let (start, end) = range.split_once('-').unwrap_or((range, range));
+    let (start, end) = (start.trim().parse().ok()?, end.trim().parse().ok()?);
+    (start <= end).then_some((start, end))
+}
+
+/// Renders a code block, highlighted if `info.lang` is known, with line numbers, emphasized
+/// lines and a caption if requested. Returns `None` if there is nothing to do, in which case the
+/// code should be rendered as it is.
+pub fn code_block(code: &str, info: &Info) -> Result<Option<String>> {
+    let syntax = Some(info.lang.as_str())
+        .filter(|l| !l.is_empty())
+        .and_then(|l| SYNTAXES.find_syntax_by_token(l));
+    if syntax.is_none() && !info.has_attributes() {
+        return Ok(None);
+    }
+
+    let lines = match syntax {
+        Some(syntax) => highlighted_lines(code, syntax)?,
+        None => LinesWithEndings::from(code).map(escape_html).collect(),
+    };
+
+    let mut html = String::new();
+    if let Some(title) = &info.title {
+        html.push_str("<figure class=\"code-block\">\n<figcaption>");
+        html.push_str(&escape_html(title));
+        html.push_str("</figcaption>\n");
+    }
+    html.push_str("<pre class=\"highlight hl-code\"><code");
+    if !info.lang.is_empty() {
+        html.push_str(&format!(" class=\"language-{}\"", escape_html(&info.lang)));
+    }
+    html.push('>');
+    for (i, line) in lines.iter().enumerate() {
+        let n = i + 1;
+        if !info.line_numbers && info.highlighted.is_empty() {
+            html.push_str(line);
+            continue;
+        }
+        if info.highlighted.iter().any(|(s, e)| (*s..=*e).contains(&n)) {
+            html.push_str("<span class=\"code-line highlighted\">");
+        } else {
+            html.push_str("<span class=\"code-line\">");
+        }
+        if info.line_numbers {
+            html.push_str(&format!("<span class=\"line-number\">{n}</span>"));
+        }
+        html.push_str(line);
+        html.push_str("</span>");
+    }
+    html.push_str("</code></pre>\n");
+    if info.title.is_some() {
+        html.push_str("</figure>\n");
+    }
+    Ok(Some(html))
+}
+
+/// Highlights `code` line by line. All spans are closed at the end of each line and opened again
+/// on the next, so that every line can be wrapped on its own.
+fn highlighted_lines(code: &str, syntax: &SyntaxReference) -> Result<Vec<String>> {
+    let mut state = ParseState::new(syntax);
+    let mut stack = ScopeStack::new();
+    let mut lines = vec![];
+    for line in LinesWithEndings::from(code) {
+        let ops = state.parse_line(line, &SYNTAXES)?;
+        let mut html: String = stack.as_slice().iter().map(|s| open_span(*s)).collect();
+        let open = stack.len() as isize;
+        let (spans, delta) =
+            syntect::html::line_tokens_to_classed_spans(line, &ops, CLASS_STYLE, &mut stack)?;
+        html.push_str(&spans);
+        html.push_str(&"</span>".repeat((open + delta).max(0) as usize));
+        lines.push(html);
+    }
+    Ok(lines)
+}
+
+/// Opening tag for `scope`, with the same classes syntect uses.
+fn open_span(scope: Scope) -> String {
+    let classes: Vec<String> = scope
+        .build_string()
+        .split('.')
+        .map(|atom| format!("hl-{atom:}"))
+        .collect();
+    format!("<span class=\"{:}\">", classes.join(" "))
+}
+
+pub fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Stylesheet coloring highlighted code like `theme`, one of syntect's default themes.
+pub fn css(theme: &str) -> Result<String> {
+    let themes = ThemeSet::load_defaults();
+    let theme = themes.themes.get(theme).ok_or_else(|| {
+        anyhow!(
+            "unknown highlight theme {theme:?}, expected one of {:}",
+            themes.themes.keys().cloned().collect::<Vec<_>>().join(", ")
+        )
+    })?;
+    let mut css = syntect::html::css_for_theme_with_class_style(theme, CLASS_STYLE)?;
+    css.push_str(LINES_CSS);
+    Ok(css)
+}
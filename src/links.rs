@@ -0,0 +1,289 @@
+use crate::diagnostics::Diagnostics;
+use anyhow::Result;
+use log::error;
+use rayon::prelude::*;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// A link that doesn't lead anywhere, or can't be checked.
+#[derive(Serialize, Debug)]
+pub struct BrokenLink {
+    // The content file the link is in, or the rendered page for links from templates.
+    pub source: PathBuf,
+    // Line of `source`, starting at 1. Unknown for rendered pages.
+    pub line: Option<usize>,
+    pub url: String,
+    pub reason: String,
+}
+
+/// The rendered site, to check internal links against. Pages are only parsed once they are
+/// needed, and then only once.
+pub struct Site<'a> {
+    render_path: &'a Path,
+    siteurl: &'a str,
+    pages: Mutex<HashMap<PathBuf, Arc<Page>>>,
+}
+
+/// What link checking needs to know about a rendered page.
+struct Page {
+    // Everything a `#fragment` can point at.
+    ids: HashSet<String>,
+    // Every `href` and `src` on the page, and whether it's an image.
+    urls: Vec<(String, bool)>,
+}
+
+impl<'a> Site<'a> {
+    pub fn new(render_path: &'a Path, siteurl: &'a str) -> Site<'a> {
+        Site {
+            render_path,
+            siteurl,
+            pages: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Checks that `url`, as linked from `page` (relative to the render directory), points at a
+    /// rendered file, and that its `#fragment` exists if it is a page. External URLs are not
+    /// checked.
+    pub fn check(&self, page: &Path, url: &str) -> Result<(), String> {
+        let Some((path, fragment)) = self.resolve(page, url) else {
+            return Ok(());
+        };
+        let mut file = self.render_path.join(path);
+        if file.is_dir() {
+            file = file.join("index.html");
+        }
+        if !file.is_file() {
+            return Err(format!("expected {file:?}"));
+        }
+
+        match fragment {
+            Some(fragment) if file.extension().is_some_and(|e| e == "html") => {
+                let target = self.page(&file).map_err(|e| format!("{e:#}"))?;
+                if !target.ids.contains(&fragment) {
+                    return Err(format!("there is no id {fragment:?} in {file:?}"));
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks every `href` and `src` of every rendered page, which includes everything coming
+    /// from templates. `checked` has the URLs already checked against the markdown they come
+    /// from, by the page they were rendered into, which are skipped. URLs relative to the page,
+    /// like `#fragment`, are only skipped on that page, since they lead elsewhere on others.
+    pub fn check_pages(
+        &self,
+        checked: &HashMap<PathBuf, HashSet<&str>>,
+    ) -> Result<Vec<BrokenLink>> {
+        let pattern = self.render_path.join("**/*.html");
+        let files = glob::glob(pattern.to_str().unwrap())?.collect::<Result<Vec<_>, _>>()?;
+        let checked_anywhere: HashSet<&str> = checked
+            .values()
+            .flatten()
+            .copied()
+            .filter(|url| !self.is_page_relative(url))
+            .collect();
+
+        let broken = files
+            .par_iter()
+            .map(|file| {
+                let path = file.strip_prefix(self.render_path)?;
+                Ok(self
+                    .page(file)?
+                    .urls
+                    .iter()
+                    .filter(|(url, _)| {
+                        !checked_anywhere.contains(url.as_str())
+                            && !checked.get(path).is_some_and(|c| c.contains(url.as_str()))
+                    })
+                    .filter_map(|(url, image)| {
+                        let reason = self.check(path, url).err()?;
+                        let kind = if *image { "image" } else { "URL" };
+                        Some(BrokenLink {
+                            source: file.clone(),
+                            line: None,
+                            url: url.clone(),
+                            reason: format!("dangling internal {kind:} {url:}, {reason:}"),
+                        })
+                    })
+                    .collect::<Vec<_>>())
+            })
+            .collect();
+        Ok(crate::all_ok(broken)?.into_iter().flatten().collect())
+    }
+
+    /// Whether `url` leads somewhere else depending on the page it is on.
+    fn is_page_relative(&self, url: &str) -> bool {
+        let url = self.strip_siteurl(url);
+        !url.starts_with('/') && !url.split(['/', '?', '#']).next().unwrap().contains(':')
+    }
+
+    /// `url` without the site url, if it starts with it.
+    fn strip_siteurl<'u>(&self, url: &'u str) -> &'u str {
+        match url.strip_prefix(self.siteurl.trim_end_matches('/')) {
+            Some(rest) if rest.is_empty() || rest.starts_with(['/', '?', '#']) => rest,
+            _ => url,
+        }
+    }
+
+    /// The target of an internal `url` on `page`, relative to the render directory, and its
+    /// fragment. `None` for external URLs.
+    fn resolve(&self, page: &Path, url: &str) -> Option<(PathBuf, Option<String>)> {
+        let url = self.strip_siteurl(url);
+        // Anything with a scheme (`https:`, `mailto:`, `data:`, ...) or a host is external.
+        if url.starts_with("//") || url.split(['/', '?', '#']).next().unwrap().contains(':') {
+            return None;
+        }
+
+        let (url, fragment) = match url.split_once('#') {
+            Some((u, f)) => (u, Some(decode(f))),
+            None => (url, None),
+        };
+        let url = decode(url.split('?').next().unwrap());
+        let path = if url.is_empty() {
+            page.to_path_buf()
+        } else if let Some(absolute) = url.strip_prefix('/') {
+            PathBuf::from(absolute)
+        } else {
+            page.parent().unwrap_or(Path::new("")).join(url)
+        };
+
+        // Resolve `.` and `..`, the target has to exist so there are no symlinks to worry about.
+        let mut normalized = PathBuf::new();
+        for c in path.components() {
+            match c {
+                Component::ParentDir => {
+                    normalized.pop();
+                }
+                Component::Normal(c) => normalized.push(c),
+                _ => {}
+            }
+        }
+        Some((normalized, fragment.filter(|f| !f.is_empty())))
+    }
+
+    fn page(&self, file: &Path) -> Result<Arc<Page>> {
+        if let Some(page) = self.pages.lock().unwrap().get(file) {
+            return Ok(page.clone());
+        }
+
+        let html = scraper::Html::parse_document(&std::fs::read_to_string(file)?);
+        let select = |selector| {
+            let selector = scraper::Selector::parse(selector).unwrap();
+            html.select(&selector).collect::<Vec<_>>()
+        };
+        let page = Arc::new(Page {
+            ids: select("[id], a[name]")
+                .into_iter()
+                .filter_map(|e| e.attr("id").or(e.attr("name")))
+                .map(str::to_owned)
+                .collect(),
+            urls: select("[href], [src]")
+                .into_iter()
+                .flat_map(|e| {
+                    let image = e.value().name() == "img";
+                    [e.attr("href"), e.attr("src")]
+                        .into_iter()
+                        .flatten()
+                        .map(move |url| (url.to_owned(), image))
+                })
+                .collect(),
+        });
+
+        self.pages
+            .lock()
+            .unwrap()
+            .insert(file.to_path_buf(), page.clone());
+        Ok(page)
+    }
+}
+
+fn decode(s: &str) -> String {
+    percent_encoding::percent_decode_str(s)
+        .decode_utf8_lossy()
+        .into_owned()
+}
+
+/// Reports all `broken` links. In `strict` mode they are errors that fail the build, otherwise
+/// they are only logged. If `report_path` is set, all of them are also written there as JSON.
+pub fn report(
+    mut broken: Vec<BrokenLink>,
+    strict: bool,
+    report_path: Option<&Path>,
+    diagnostics: &Diagnostics,
+) -> Result<()> {
+    broken.sort_by(|a, b| (&a.source, a.line).cmp(&(&b.source, b.line)));
+
+    for b in broken.iter() {
+        if strict {
+            diagnostics.error(&b.source, b.line, &b.reason);
+        } else {
+            match b.line {
+                Some(line) => error!("{:}:{:}: {:}", b.source.display(), line, b.reason),
+                None => error!("{:}: {:}", b.source.display(), b.reason),
+            }
+        }
+    }
+
+    if let Some(path) = report_path {
+        std::fs::write(path, serde_json::to_string_pretty(&broken)?)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_pages_skips_checked_urls_by_page() {
+        let dir = tempfile::tempdir().unwrap();
+        let post = Path::new("blog/post/index.html");
+        std::fs::create_dir_all(dir.path().join("blog/post")).unwrap();
+        std::fs::write(
+            dir.path().join(post),
+            r##"<span id="eq:late"></span><a href="#eq:late">1</a><a href="/missing/">2</a>"##,
+        )
+        .unwrap();
+        // A summary of the post, without the equation.
+        std::fs::write(
+            dir.path().join("index.html"),
+            r##"<a href="#eq:late">1</a><a href="/missing/">2</a><a href="/gone/">3</a>"##,
+        )
+        .unwrap();
+
+        let site = Site::new(dir.path(), "https://example.com");
+        let checked =
+            HashMap::from([(post.to_path_buf(), HashSet::from(["#eq:late", "/missing/"]))]);
+        let broken = site.check_pages(&checked).unwrap();
+        let mut broken: Vec<(String, &str)> = broken
+            .iter()
+            .map(|b| {
+                let source = b.source.strip_prefix(dir.path()).unwrap();
+                (source.to_string_lossy().into_owned(), b.url.as_str())
+            })
+            .collect();
+        broken.sort();
+        assert_eq!(
+            broken,
+            [
+                ("index.html".to_owned(), "#eq:late"),
+                ("index.html".to_owned(), "/gone/"),
+            ]
+        );
+    }
+
+    #[test]
+    fn is_page_relative() {
+        let site = Site::new(Path::new(""), "https://example.com/");
+        assert!(site.is_page_relative("#fig:a"));
+        assert!(site.is_page_relative("image.png"));
+        assert!(!site.is_page_relative("/blog/"));
+        assert!(!site.is_page_relative("https://example.com/blog/#top"));
+        assert!(!site.is_page_relative("https://other.org/#top"));
+        assert!(!site.is_page_relative("mailto:me@example.com"));
+    }
+}
@@ -1,15 +1,29 @@
 use anyhow::{anyhow, bail, Result};
 use clap::Parser;
-use log::{error, info};
-use pulldown_cmark::Event;
+use diagnostics::Diagnostics;
+use log::info;
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs;
 use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
 
+mod archive;
+mod bibliography;
+mod cache;
+mod diagnostics;
+mod external;
+mod frontmatter;
+mod highlight;
+mod links;
 mod markdown;
+mod math;
 mod render;
+mod serve;
+// TODO(swj): Re-enable once dependencies checked into repos are ignored, see `build`.
+#[allow(dead_code)]
 mod stats;
 
 #[derive(clap::Parser, Debug)]
@@ -18,12 +32,54 @@ struct Flags {
     #[arg(long)]
     input: String,
 
-    #[arg(long)]
-    output: String,
+    /// Where to write the rendered site. Required when building, optional for `serve`, which
+    /// defaults to a temporary directory.
+    #[arg(long, global = true)]
+    output: Option<String>,
 
     /// The site url can be overriden for local development.
-    #[arg(long)]
+    #[arg(long, global = true)]
     siteurl: Option<String>,
+
+    /// Ignore the build cache and render everything from scratch.
+    #[arg(long, global = true)]
+    clean: bool,
+
+    /// Fail the build on broken links, instead of only logging them. Can also be enabled with
+    /// `strict = true` in the config.
+    #[arg(long, global = true)]
+    strict: bool,
+
+    /// Write a JSON report of all broken links to this file.
+    #[arg(long, global = true)]
+    link_report: Option<String>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Serve the site locally, rebuilding and reloading open pages whenever content or templates
+    /// change.
+    Serve {
+        #[arg(long, default_value_t = 8787)]
+        port: u16,
+    },
+    /// Check that external links in the content still work, reporting dead and redirected ones.
+    CheckExternal {
+        /// How many requests to send to the same host at once.
+        #[arg(long, default_value_t = 2, value_parser = clap::value_parser!(u64).range(1..))]
+        per_host: u64,
+
+        /// Seconds to wait for a response.
+        #[arg(long, default_value_t = 10)]
+        timeout: u64,
+
+        /// Days before a previous result is checked again.
+        #[arg(long, default_value_t = 7)]
+        ttl_days: u64,
+    },
 }
 
 #[derive(Deserialize, Debug)]
@@ -36,11 +92,80 @@ struct Config {
     templates_path: String,
     feed_all_atom: String,
     feed_all_rss: String,
+    #[serde(default = "default_feed_all_json")]
+    feed_all_json: String,
+    // What the feeds contain, only the summary by default.
+    #[serde(default)]
+    feed_all_atom_content: render::FeedContent,
+    #[serde(default)]
+    feed_all_rss_content: render::FeedContent,
+    #[serde(default)]
+    feed_all_json_content: render::FeedContent,
+    // Where the feeds of each tag are written to, `{tag}` is replaced by the tag.
+    #[serde(default = "default_tag_feed_atom")]
+    tag_feed_atom: String,
+    #[serde(default = "default_tag_feed_rss")]
+    tag_feed_rss: String,
+    #[serde(default = "default_tag_feed_json")]
+    tag_feed_json: String,
+    // What the feeds of each tag contain, only the summary by default.
+    #[serde(default)]
+    tag_feed_atom_content: render::FeedContent,
+    #[serde(default)]
+    tag_feed_rss_content: render::FeedContent,
+    #[serde(default)]
+    tag_feed_json_content: render::FeedContent,
     max_feed_entries: usize,
+    // Colors for syntax highlighting, one of syntect's default themes.
+    #[serde(default = "default_highlight_theme")]
+    highlight_theme: String,
+    // Where the stylesheet for syntax highlighting is written to.
+    #[serde(default = "default_highlight_css")]
+    highlight_css: String,
+    // Broken links fail the build.
+    #[serde(default)]
+    strict: bool,
+    // Where footnotes are shown, unless a post says otherwise.
+    #[serde(default)]
+    footnotes: markdown::FootnoteStyle,
+    // Length of summaries, for posts without a `<!-- more -->` marker or `summary` metadata.
+    #[serde(default = "default_summary_words")]
+    summary_words: usize,
+    // Only used by `stats`, which is currently disabled.
+    #[allow(dead_code)]
     github_user: String,
+    #[allow(dead_code)]
     github_access_token: String,
 }
 
+fn default_highlight_theme() -> String {
+    "InspiredGitHub".to_owned()
+}
+
+fn default_highlight_css() -> String {
+    "css/highlight.css".to_owned()
+}
+
+fn default_feed_all_json() -> String {
+    "feeds/all.json".to_owned()
+}
+
+fn default_tag_feed_atom() -> String {
+    "tags/{tag}/feed.atom.xml".to_owned()
+}
+
+fn default_tag_feed_rss() -> String {
+    "tags/{tag}/feed.rss.xml".to_owned()
+}
+
+fn default_tag_feed_json() -> String {
+    "tags/{tag}/feed.json".to_owned()
+}
+
+fn default_summary_words() -> usize {
+    100
+}
+
 #[derive(PartialEq, Debug)]
 enum ContentStatus {
     Public,
@@ -64,12 +189,36 @@ impl TryFrom<&str> for ContentStatus {
 struct RawContent {
     // Path relative to the root of the website.
     path: PathBuf,
+    // The file this was read from, for error messages.
+    source: PathBuf,
+    // Line of the source file the markdown starts on.
+    body_line: usize,
     // Markdown contents of the file.
     markdown: String,
-    metadata: HashMap<String, String>,
+    title: String,
+    layout: Option<String>,
+    metadata: frontmatter::Metadata,
     timestamp: chrono::NaiveDateTime,
+    // When the post was last changed, if it says so.
+    modified: Option<chrono::NaiveDateTime>,
     status: ContentStatus,
     tags: Vec<String>,
+    // The `.bib` file citations are looked up in, relative to the post.
+    bibliography: Option<PathBuf>,
+    // From the metadata, or else the site's `footnotes`, see `build`.
+    footnotes: Option<markdown::FootnoteStyle>,
+    // Markdown from the metadata, instead of the beginning of the post.
+    summary: Option<String>,
+    // Plain text from the metadata, instead of the beginning of the post.
+    description: Option<String>,
+    // The site's `summary_words`, see `build`.
+    summary_words: usize,
+    // The site's `siteurl`, see `build`.
+    siteurl: String,
+    // Links marked for archiving which have a snapshot, see `build`.
+    archived: Vec<String>,
+    // Analyzed on first use, then shared by the page itself and every listing including it.
+    article: OnceLock<Article>,
 }
 
 struct StaticContent {
@@ -79,10 +228,27 @@ struct StaticContent {
 
 enum RawFile {
     Static(StaticContent),
-    Content(RawContent),
+    Content(Box<RawContent>),
 }
 
-fn read_source_files(current: &Path, prefix: &Path) -> Result<Vec<RawFile>> {
+/// How to build, as opposed to `Config`, which describes what to build.
+#[derive(Clone, Debug, Default)]
+struct BuildOptions {
+    // Ignore the build cache and render everything from scratch.
+    clean: bool,
+    // Don't fetch snapshots of links marked for archiving, only use the ones there are.
+    offline: bool,
+    // Where to write the JSON report of broken links, if anywhere.
+    link_report: Option<PathBuf>,
+}
+
+/// Reads all files below `current`. Content files with problems are reported to `diagnostics`
+/// and skipped.
+fn read_source_files(
+    current: &Path,
+    prefix: &Path,
+    diagnostics: &Diagnostics,
+) -> Result<Vec<RawFile>> {
     let mut files = Vec::new();
 
     for entry in fs::read_dir(current)? {
@@ -90,84 +256,24 @@ fn read_source_files(current: &Path, prefix: &Path) -> Result<Vec<RawFile>> {
         if path.is_dir() {
             files.extend(read_source_files(
                 &path,
-                &prefix.join(path.components().last().unwrap()),
+                &prefix.join(path.components().next_back().unwrap()),
+                diagnostics,
             )?);
         } else if path.ends_with(".DS_Store") {
             // Ignore Mac OS settings file.
         } else if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
             match ext {
-                "markdown" | "md" => {
-                    let contents = std::fs::read_to_string(&path)?;
-
-                    let (metadata, markdown) = contents
-                        .split_once("\n\n")
-                        .ok_or(anyhow!("Must have metadata!"))?;
-                    assert!(metadata.contains("title: "));
-
-                    let metadata = metadata
-                        .split("\n")
-                        .map(|l| {
-                            l.split_once(": ")
-                                .map(|(k, v)| (k.to_ascii_lowercase(), v.to_owned()))
-                                .ok_or(anyhow!("Metadata must be : delimited"))
-                        })
-                        .collect::<Result<HashMap<String, String>>>()?;
-
-                    let mut date = metadata
-                        .get("date")
-                        .ok_or(anyhow!("Must have date metadata: {:?}", metadata))?
-                        .clone();
-                    if !date.contains(" ") {
-                        date.push_str(" 00:00");
-                    }
-                    let date = chrono::NaiveDateTime::parse_from_str(&date, "%Y-%m-%d %H:%M")?;
-
-                    let status = metadata
-                        .get("status")
-                        .unwrap_or(&"public".to_owned())
-                        .as_str()
-                        .try_into()?;
-
-                    let path = if let Some(p) = metadata.get("save_as") {
-                        Path::new(p).to_path_buf()
-                    } else {
-                        let slug = metadata
-                            .get("title")
-                            .unwrap()
-                            .chars()
-                            .filter(|c| c.is_alphanumeric() || c.is_ascii_whitespace())
-                            .collect::<String>()
-                            .to_ascii_lowercase()
-                            .replace(' ', "-");
-                        if metadata.get("layout").unwrap_or(&String::new()) == "page" {
-                            PathBuf::new().join(slug)
-                        } else {
-                            PathBuf::new()
-                                .join("blog")
-                                .join(date.format("%Y/%m/%d").to_string())
-                                .join(slug)
+                "markdown" | "md" => match std::fs::read_to_string(&path) {
+                    Ok(contents) => {
+                        let contents = contents
+                            .trim_start_matches('\u{feff}')
+                            .replace("\r\n", "\n");
+                        if let Some(c) = read_content(&path, &contents, diagnostics) {
+                            files.push(RawFile::Content(Box::new(c)));
                         }
-                        // prefix.join(path.file_stem().unwrap())
-                    };
-                    let tags = if let Some(tag_str) = metadata.get("tags") {
-                        tag_str
-                            .split(",")
-                            .map(|t| t.trim().to_owned())
-                            .filter(|t| !t.is_empty())
-                            .collect()
-                    } else {
-                        vec![]
-                    };
-
-                    files.push(RawFile::Content(RawContent {
-                        path: path,
-                        metadata,
-                        markdown: markdown.to_owned(),
-                        timestamp: date,
-                        status,
-                        tags,
-                    }))
-                }
+                    }
+                    Err(e) => diagnostics.error(&path, None, format!("failed to read: {e:}")),
+                },
                 "py" => {}
                 _ => files.push(RawFile::Static(StaticContent {
                     path: if prefix.to_string_lossy() == "extra" {
@@ -184,61 +290,331 @@ fn read_source_files(current: &Path, prefix: &Path) -> Result<Vec<RawFile>> {
     Ok(files)
 }
 
-fn to_html(markdown: &str) -> Result<String> {
-    let events = markdown::to_events(markdown)?;
-    let mut content = String::new();
-    pulldown_cmark::html::push_html(&mut content, events.into_iter());
-    Ok(content)
+/// Parses a markdown file with its metadata header. Reports every problem found to
+/// `diagnostics`, and returns `None` if there were any.
+fn read_content(source: &Path, contents: &str, diagnostics: &Diagnostics) -> Option<RawContent> {
+    let document = match frontmatter::parse(contents) {
+        Ok(d) => d,
+        Err(errors) => {
+            for e in errors {
+                diagnostics.error(source, Some(e.line), e.message);
+            }
+            return None;
+        }
+    };
+    let metadata = document.metadata;
+    let mut errors = Vec::new();
+    let mut string = |key| {
+        metadata.string(key).unwrap_or_else(|e| {
+            errors.push(e);
+            None
+        })
+    };
+    let title = string("title");
+    let date = string("date");
+    let modified = string("modified");
+    let status = string("status");
+    let layout = string("layout");
+    let save_as = string("save_as");
+    let bibliography = string("bibliography");
+    let footnotes = string("footnotes");
+    let summary = string("summary");
+    let description = string("description");
+    let tags = metadata.list("tags").unwrap_or_else(|e| {
+        errors.push(e);
+        vec![]
+    });
+    let ok = errors.is_empty();
+    for e in errors {
+        diagnostics.error(source, Some(e.line), e.message);
+    }
+
+    if title.is_none() {
+        diagnostics.error(source, Some(1), "missing `title` metadata");
+    }
+
+    let date_metadata = |key, d: &str| {
+        let date = parse_date(d);
+        if date.is_none() {
+            diagnostics.error(
+                source,
+                Some(metadata.line(key)),
+                format!("invalid {key:} {d:?}, expected YYYY-MM-DD, optionally followed by a time"),
+            );
+        }
+        date
+    };
+    let date = match date {
+        Some(d) => date_metadata("date", &d),
+        None => {
+            diagnostics.error(source, Some(1), "missing `date` metadata");
+            None
+        }
+    };
+    let modified = modified.map(|d| date_metadata("modified", &d));
+
+    let status: Option<ContentStatus> = status
+        .as_deref()
+        .unwrap_or("public")
+        .try_into()
+        .map_err(|e| diagnostics.error(source, Some(metadata.line("status")), format!("{e:}")))
+        .ok();
+
+    let footnotes: Option<Option<markdown::FootnoteStyle>> = match footnotes {
+        Some(f) => markdown::FootnoteStyle::try_from(f.as_str())
+            .map(Some)
+            .map_err(|e| {
+                diagnostics.error(source, Some(metadata.line("footnotes")), format!("{e:}"))
+            })
+            .ok(),
+        None => Some(None),
+    };
+
+    let (Some(title), Some(date), Some(status), Some(footnotes), Some(modified), true) = (
+        title,
+        date,
+        status,
+        footnotes,
+        modified.map_or(Some(None), |m| m.map(Some)),
+        ok,
+    ) else {
+        return None;
+    };
+
+    let path = if let Some(p) = save_as {
+        PathBuf::from(p)
+    } else {
+        let slug = title
+            .chars()
+            .filter(|c| c.is_alphanumeric() || c.is_ascii_whitespace())
+            .collect::<String>()
+            .to_ascii_lowercase()
+            .replace(' ', "-");
+        if layout.as_deref() == Some("page") {
+            PathBuf::new().join(slug)
+        } else {
+            PathBuf::new()
+                .join("blog")
+                .join(date.format("%Y/%m/%d").to_string())
+                .join(slug)
+        }
+        // prefix.join(path.file_stem().unwrap())
+    };
+
+    Some(RawContent {
+        path,
+        source: source.to_path_buf(),
+        body_line: document.body_line,
+        markdown: document.body.to_owned(),
+        title,
+        layout,
+        metadata,
+        timestamp: date,
+        modified,
+        status,
+        tags,
+        bibliography: bibliography.map(|b| source.parent().unwrap_or(Path::new("")).join(b)),
+        footnotes,
+        summary,
+        description,
+        summary_words: default_summary_words(),
+        siteurl: String::new(),
+        archived: vec![],
+        article: OnceLock::new(),
+    })
+}
+
+/// Parses dates like `2024-01-02`, `2024-01-02 10:30` or RFC 3339 timestamps. Time zones are
+/// ignored, all dates are local to the author.
+fn parse_date(date: &str) -> Option<chrono::NaiveDateTime> {
+    if let Ok(d) = chrono::DateTime::parse_from_rfc3339(date) {
+        return Some(d.naive_local());
+    }
+    if let Ok(d) = chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d") {
+        return d.and_hms_opt(0, 0, 0);
+    }
+    [
+        "%Y-%m-%d %H:%M",
+        "%Y-%m-%d %H:%M:%S",
+        "%Y-%m-%dT%H:%M",
+        "%Y-%m-%dT%H:%M:%S",
+    ]
+    .iter()
+    .find_map(|f| chrono::NaiveDateTime::parse_from_str(date, f).ok())
 }
 
 impl RawContent {
-    fn validate_links(&self, output_path: &Path) -> Result<()> {
-        let events = markdown::to_events(&self.markdown)?;
-        for e in events {
-            if let Event::Start(pulldown_cmark::Tag::Link { dest_url: url, .. }) = e {
-                // Verify that internal links are valid.
-                if url.starts_with("/") {
-                    let url = url.trim_matches('/');
-                    // Strip # anchor links.
-                    let url = url.split_once('#').map(|(a, _)| a).unwrap_or(url);
-
-                    let target_file = output_path.join(&url);
-                    if !target_file.exists() {
-                        error!(
-                            "Dangling internal URL in {:?}: {:}, expected {:?}",
-                            self.path, url, target_file
-                        );
-                    }
-                } else if !url.starts_with("http") && !url.starts_with("mailto") {
-                    error!("Interal URLs should be absolute {:?}, external URLs should start with https://, got: {:}", self.path, url);
-                }
+    /// Where the rendered page goes, relative to the output directory.
+    fn output_path(&self) -> PathBuf {
+        let path = self.path.join("index.html");
+        if self.status == ContentStatus::Draft {
+            Path::new("draft").join(path)
+        } else {
+            path
+        }
+    }
+
+    fn layout(&self) -> &str {
+        self.layout.as_deref().unwrap_or("page")
+    }
+
+    /// Hash of the source of this file, see `cache::Fingerprint`.
+    fn fingerprint(&self) -> Result<String> {
+        let mut fingerprint = cache::Fingerprint::default()
+            .add(self.path.to_string_lossy().as_bytes())
+            .add(&self.markdown)
+            .add_serialized(self.metadata.values())?
+            // These come from the config.
+            .add(format!(
+                "{:?} {:} {:}",
+                self.footnotes, self.summary_words, self.siteurl
+            ))
+            .add_serialized(&self.archived)?;
+        // Included files are part of the source too.
+        for path in self.article()?.includes.iter() {
+            fingerprint = fingerprint
+                .add(path.to_string_lossy().as_bytes())
+                .add(std::fs::read(path).unwrap_or_default());
+        }
+        Ok(fingerprint.finish())
+    }
+
+    /// Checks the links and images in the markdown against the rendered `site`.
+    fn validate_links(&self, site: &links::Site) -> Result<Vec<links::BrokenLink>> {
+        let page = self.output_path();
+        let mut broken = Vec::new();
+        for markdown::Link {
+            url, line, image, ..
+        } in self.article()?.links.iter()
+        {
+            let mut broken_link = |reason| {
+                broken.push(links::BrokenLink {
+                    source: self.source.clone(),
+                    line: Some(self.body_line + line - 1),
+                    url: url.clone(),
+                    reason,
+                })
+            };
+            let kind = if *image { "image" } else { "URL" };
+            if !url.starts_with(['/', '#'])
+                && !url.starts_with("http")
+                && !url.starts_with("mailto")
+            {
+                broken_link(format!("internal URLs should be absolute, external URLs should start with https://, got {url:}"));
+            } else if let Err(reason) = site.check(&page, url) {
+                broken_link(format!("dangling internal {kind:} {url:}, {reason:}"));
             }
         }
 
-        Ok(())
+        Ok(broken)
+    }
+
+    /// Makes sure this can be rendered, reporting any problems to `diagnostics`.
+    fn check(&self, jinja: &minijinja::Environment, diagnostics: &Diagnostics) -> bool {
+        if jinja
+            .get_template(&format!("{:}.html", self.layout()))
+            .is_err()
+        {
+            diagnostics.error(
+                &self.source,
+                Some(self.metadata.line("layout")),
+                format!(
+                    "unknown layout {:?}, there is no {:}.html template",
+                    self.layout(),
+                    self.layout()
+                ),
+            );
+            return false;
+        }
+        match self.article() {
+            Ok(article) => {
+                for w in article.warnings.iter() {
+                    diagnostics.warning(
+                        &self.source,
+                        Some(self.body_line + w.line - 1),
+                        &w.message,
+                    );
+                }
+                true
+            }
+            Err(e) => {
+                if let Some(err) = e.downcast_ref::<markdown::Error>() {
+                    diagnostics.error(
+                        &self.source,
+                        Some(self.body_line + err.line - 1),
+                        &err.message,
+                    )
+                } else if let Some(err) = e.downcast_ref::<frontmatter::Error>() {
+                    diagnostics.error(&self.source, Some(err.line), &err.message)
+                } else {
+                    diagnostics.error(&self.source, None, format!("{e:#}"))
+                }
+                false
+            }
+        }
     }
 
-    fn to_article(&self) -> Result<Article> {
-        let title = self
-            .metadata
-            .get("title")
-            .ok_or(anyhow!("Must have title!"))?;
+    /// The rendered article, the markdown is only analyzed the first time this is called.
+    fn article(&self) -> Result<&Article> {
+        if let Some(article) = self.article.get() {
+            return Ok(article);
+        }
+        let article = self.analyze()?;
+        Ok(self.article.get_or_init(|| article))
+    }
 
-        let mut summary_markdown = self
-            .markdown
-            .split_inclusive([' ', '\n'])
-            .take(100)
-            .collect::<Vec<_>>()
-            .join("");
-        summary_markdown.push_str("...");
+    fn analyze(&self) -> Result<Article> {
+        let dir = self.source.parent().unwrap_or(Path::new(""));
+        let bibliography = match &self.bibliography {
+            Some(path) => Some(bibliography::Bibliography::load(path)?),
+            None => None,
+        };
+        let options = markdown::Options {
+            dir,
+            bibliography: bibliography.as_ref(),
+            archived: &self.archived,
+            footnotes: self.footnotes.unwrap_or_default(),
+            summary_words: self.summary_words,
+        };
+        let mut analysis = markdown::analyze(&self.markdown, &options)?;
+        analysis.includes.extend(self.bibliography.clone());
+        let url = self.path.to_str().unwrap().to_owned();
+        let description = self.description.clone().unwrap_or(analysis.description);
+        let summary = match &self.summary {
+            // Problems with the summary are reported on its line of the metadata.
+            Some(summary) => {
+                markdown::to_html(markdown::to_events(summary, &options).map_err(|e| {
+                    frontmatter::Error {
+                        line: self.metadata.line("summary"),
+                        message: match e.downcast_ref::<markdown::Error>() {
+                            Some(err) => format!("in `summary`: {:}", err.message),
+                            None => format!("in `summary`: {e:#}"),
+                        },
+                    }
+                })?)
+            }
+            None => analysis.summary,
+        };
 
         Ok(Article {
-            title: title.clone(),
-            url: self.path.to_str().unwrap().to_owned(),
-            summary: to_html(&summary_markdown)?,
-            content: to_html(&self.markdown)?,
+            title: self.title.clone(),
+            url: url.clone(),
+            summary: markdown::detach_summary(
+                &summary,
+                &format!("{:}/{:}/", self.siteurl.trim_end_matches('/'), url),
+            )?,
+            description_html: highlight::escape_html(&description),
+            description,
+            content: analysis.html,
+            headings: analysis.headings,
+            toc: analysis.toc,
+            meta: self.metadata.values().clone(),
+            links: analysis.links,
+            includes: analysis.includes,
+            warnings: analysis.warnings,
             tags: self.tags.clone(),
             timestamp: self.timestamp,
+            modified: self.modified,
             locale_date: self.timestamp.format("%a %d %B %Y").to_string(),
         })
     }
@@ -246,36 +622,36 @@ impl RawContent {
 
 fn render_content(
     f: &RawContent,
-    output_path: &Path,
     jinja: &minijinja::Environment,
     base_context: &minijinja::Value,
-) -> Result<()> {
-    let output_path = if f.status == ContentStatus::Draft {
-        output_path.join("draft")
-    } else {
-        output_path.to_path_buf()
-    };
-    let dst = output_path.join(&f.path).join("index.html");
-
-    std::fs::create_dir_all(dst.parent().unwrap())?;
-
-    let tmpl = jinja.get_template(&format!(
-        "{:}.html",
-        f.metadata
-            .get("layout")
-            .map(|p| p.as_str())
-            .unwrap_or("page")
-    ))?;
-    let html_output = tmpl.render(minijinja::context! {
-    article => f.to_article()?,
-    ..base_context.clone()})?;
-
-    std::fs::write(dst, &html_output)?;
+) -> Result<String> {
+    let tmpl = jinja.get_template(&format!("{:}.html", f.layout()))?;
+    Ok(tmpl.render(minijinja::context! {
+    article => f.article()?,
+    ..base_context.clone()})?)
+}
 
-    Ok(())
+/// Like collecting into a `Result<Vec<T>>`, but reports every error instead of only the first.
+fn all_ok<T>(results: Vec<Result<T>>) -> Result<Vec<T>> {
+    let mut values = Vec::with_capacity(results.len());
+    let mut errors = Vec::new();
+    for r in results {
+        match r {
+            Ok(v) => values.push(v),
+            Err(e) => errors.push(format!("{e:#}")),
+        }
+    }
+    if !errors.is_empty() {
+        bail!("{:} errors:\n{:}", errors.len(), errors.join("\n"));
+    }
+    Ok(values)
 }
 
-fn read_templates(template_path: &Path) -> Result<minijinja::Environment> {
+/// Loads all templates, templates that fail to parse are reported to `diagnostics` and skipped.
+fn read_templates(
+    template_path: &Path,
+    diagnostics: &Diagnostics,
+) -> Result<minijinja::Environment<'static>> {
     let mut env = minijinja::Environment::new();
     env.set_auto_escape_callback(|_| minijinja::AutoEscape::None);
 
@@ -284,7 +660,10 @@ fn read_templates(template_path: &Path) -> Result<minijinja::Environment> {
         if path.is_file() {
             let name = path.strip_prefix(template_path)?.to_str().unwrap();
             info!("loading template {path:?} as {name:?}");
-            env.add_template_owned(name.to_owned(), std::fs::read_to_string(&path)?)?;
+            if let Err(e) = env.add_template_owned(name.to_owned(), std::fs::read_to_string(&path)?)
+            {
+                diagnostics.error(&path, e.line(), e.detail().unwrap_or("syntax error"));
+            }
         }
     }
 
@@ -292,8 +671,8 @@ fn read_templates(template_path: &Path) -> Result<minijinja::Environment> {
 }
 
 #[derive(Serialize, Debug)]
-struct ArticlesPage {
-    object_list: Vec<Article>,
+struct ArticlesPage<'a> {
+    object_list: Vec<&'a Article>,
 }
 
 #[derive(Serialize, Debug, Clone)]
@@ -302,8 +681,26 @@ struct Article {
     url: String,
     content: String,
     summary: String,
+    // Plain text, for feeds. Templates get `description_html` instead.
+    #[serde(skip)]
+    description: String,
+    // The description escaped for HTML, for `<meta name="description">` and the like.
+    description_html: String,
+    headings: Vec<markdown::Heading>,
+    // The headings, nested by level.
+    toc: Vec<markdown::TocEntry>,
+    // All metadata from the header, including keys kaihan itself doesn't know about.
+    meta: serde_json::Map<String, serde_json::Value>,
+    #[serde(skip)]
+    links: Vec<markdown::Link>,
+    #[serde(skip)]
+    includes: Vec<PathBuf>,
+    // Reported by `RawContent::check`, relative to the start of the markdown.
+    #[serde(skip)]
+    warnings: Vec<markdown::Error>,
     tags: Vec<String>,
     timestamp: chrono::NaiveDateTime,
+    modified: Option<chrono::NaiveDateTime>,
     locale_date: String,
 }
 
@@ -331,33 +728,160 @@ async fn main() -> Result<()> {
     let flags = Flags::try_parse()?;
 
     let blog_path = Path::new(&flags.input);
+    let options = BuildOptions {
+        clean: flags.clean,
+        offline: false,
+        link_report: flags.link_report.map(PathBuf::from),
+    };
+
+    match flags.command {
+        Some(Command::Serve { port }) => {
+            let siteurl = flags
+                .siteurl
+                .unwrap_or_else(|| format!("http://localhost:{port}"));
+            serve::serve(
+                blog_path,
+                siteurl,
+                flags.strict,
+                flags.output.map(PathBuf::from),
+                options,
+                port,
+            )
+            .await
+        }
+        Some(Command::CheckExternal {
+            per_host,
+            timeout,
+            ttl_days,
+        }) => {
+            let config = load_config(blog_path, flags.siteurl, flags.strict)?;
+            let limits = external::Limits {
+                per_host: per_host as usize,
+                timeout: std::time::Duration::from_secs(timeout),
+                ttl: std::time::Duration::from_secs(ttl_days * 24 * 60 * 60),
+            };
+            external::check(blog_path, &config, &limits).await
+        }
+        None => {
+            let config = load_config(blog_path, flags.siteurl, flags.strict)?;
+            let output = flags
+                .output
+                .ok_or(anyhow!("--output is required when building"))?;
+            // Snapshots are fetched with a blocking client, which must not run on the runtime.
+            tokio::task::block_in_place(|| build(blog_path, &config, Path::new(&output), &options))
+        }
+    }
+}
 
+fn load_config(blog_path: &Path, siteurl: Option<String>, strict: bool) -> Result<Config> {
     let mut config: Config =
         toml::from_str(&std::fs::read_to_string(blog_path.join("config.toml"))?)?;
-    if let Some(u) = flags.siteurl {
+    if let Some(u) = siteurl {
         config.siteurl = u;
     }
+    config.strict |= strict;
+    // JSON feeds have a single field for HTML, so they can't have both.
+    for (key, content) in [
+        ("feed_all_json_content", config.feed_all_json_content),
+        ("tag_feed_json_content", config.tag_feed_json_content),
+    ] {
+        if content == render::FeedContent::Both {
+            bail!("`{key:}` can't be \"both\" in config.toml, expected \"summary\" or \"full\"");
+        }
+    }
+    Ok(config)
+}
 
+/// Renders the whole site from `blog_path` into `render_path`. Outputs whose inputs didn't change
+/// since the last build are left untouched, unless `options.clean` is set.
+fn build(
+    blog_path: &Path,
+    config: &Config,
+    render_path: &Path,
+    options: &BuildOptions,
+) -> Result<()> {
     let content_path = blog_path.join(&config.content_path);
     let templates_path = blog_path.join(&config.templates_path);
 
-    let render_path = Path::new(&flags.output);
+    let cache = cache::BuildCache::open(render_path, options.clean)?;
 
-    let _ = std::fs::remove_dir_all(render_path);
-    std::fs::create_dir_all(render_path)?;
+    let diagnostics = Diagnostics::default();
+    let mut files = read_source_files(&content_path, Path::new(""), &diagnostics)?;
+    let jinja = read_templates(&templates_path, &diagnostics)?;
+    // Settings from the config, which aren't known yet when the content is read.
+    for f in files.iter_mut() {
+        if let RawFile::Content(c) = f {
+            c.footnotes.get_or_insert(config.footnotes);
+            c.summary_words = config.summary_words;
+            c.siteurl = config.siteurl.clone();
+        }
+    }
 
-    let files = read_source_files(&content_path, Path::new(""))?;
-    let jinja = read_templates(&templates_path)?;
+    // Snapshot links marked for archiving before the content is analyzed, so that posts only
+    // link to snapshots that exist. All snapshots are published with the site.
+    let archive = archive::Archive::open(blog_path)?;
+    let mut to_archive: BTreeMap<String, (PathBuf, usize)> = BTreeMap::new();
+    for f in files.iter_mut() {
+        if let RawFile::Content(c) = f {
+            for (url, line) in markdown::archive_links(&c.markdown) {
+                to_archive
+                    .entry(url.clone())
+                    .or_insert_with(|| (c.source.clone(), c.body_line + line - 1));
+                c.archived.push(url);
+            }
+        }
+    }
+    if !options.offline {
+        to_archive.par_iter().for_each(|(url, (source, line))| {
+            if let Err(e) = archive.snapshot(url) {
+                diagnostics.warning(
+                    source,
+                    Some(*line),
+                    format!("failed to archive {url:}: {e:#}"),
+                );
+            }
+        });
+    }
+    for f in files.iter_mut() {
+        if let RawFile::Content(c) = f {
+            c.archived.retain(|url| archive.contains(url));
+        }
+    }
+    files.extend(archive.finish(&diagnostics)?);
+
+    // Check everything up front, so that broken content is left out of all listings.
+    let usable: Vec<bool> = files
+        .par_iter()
+        .map(|f| match f {
+            RawFile::Content(c) => c.check(&jinja, &diagnostics),
+            RawFile::Static(_) => true,
+        })
+        .collect();
+    let mut usable = usable.into_iter();
+    files.retain(|_| usable.next().unwrap());
+
+    // Template errors are reported against the template, or the source being rendered.
+    let report_render = |source: &Path, result: Result<()>| {
+        if let Err(e) = result {
+            diagnostics.template_error(&templates_path, source, &e);
+        }
+    };
+    let templates = cache::template_fingerprints(&jinja);
+    let template_fingerprint = |name: &str| {
+        templates
+            .get(name)
+            .ok_or(anyhow!("template not found: {name:}"))
+    };
 
     let mut by_layout: HashMap<&str, Vec<&RawContent>> = HashMap::new();
-    let mut by_tag: HashMap<&str, Vec<&RawContent>> = HashMap::new();
+    let mut by_tag: BTreeMap<&str, Vec<&RawContent>> = BTreeMap::new();
     for f in files.iter() {
         if let RawFile::Content(c) = f {
             if c.status != ContentStatus::Public {
                 continue;
             }
 
-            if let Some(layout) = c.metadata.get("layout") {
+            if let Some(layout) = &c.layout {
                 by_layout.entry(layout).or_default().push(c);
             }
             c.tags
@@ -372,20 +896,43 @@ async fn main() -> Result<()> {
         println!("{:} {:}s", entries.len(), layout);
     }
 
-    let recent_articles = by_layout
+    // Fingerprint of a listing of posts, covering their sources and order.
+    let posts_fingerprint = |posts: &[&RawContent]| -> Result<cache::Fingerprint> {
+        posts
+            .iter()
+            .try_fold(cache::Fingerprint::default(), |f, p| {
+                Ok(f.add(p.fingerprint()?))
+            })
+    };
+
+    // Feeds only depend on the posts they contain.
+    let feed_fingerprint = |posts: &[&RawContent]| -> Result<cache::Fingerprint> {
+        Ok(
+            posts_fingerprint(&posts[..posts.len().min(config.max_feed_entries)])?
+                .add(&config.author)
+                .add(&config.sitename)
+                .add(&config.siteurl)
+                .add(format!(
+                    "{:?} {:?} {:?}",
+                    config.feed_all_atom_content,
+                    config.feed_all_rss_content,
+                    config.feed_all_json_content
+                )),
+        )
+    };
+
+    let recent_posts: Vec<&RawContent> = by_layout
         .get("post")
-        .unwrap()
-        .iter()
-        .rev()
-        .map(|p| p.to_article())
-        .collect::<Result<Vec<Article>>>()?;
+        .map(|posts| posts.iter().rev().copied().collect())
+        .unwrap_or_default();
+    let recent_articles = all_ok(recent_posts.par_iter().map(|p| p.article()).collect())?;
 
     let mut pages = by_layout
         .get("page")
-        .unwrap()
-        .iter()
-        .map(|p| p.to_article())
-        .collect::<Result<Vec<Article>>>()?;
+        .into_iter()
+        .flatten()
+        .map(|p| p.article())
+        .collect::<Result<Vec<&Article>>>()?;
     pages.sort_by(|a, b| a.title.cmp(&b.title));
 
     let base_context = minijinja::context! {
@@ -397,20 +944,37 @@ async fn main() -> Result<()> {
         DISPLAY_PAGES_ON_MENU => true,
         FEED_ALL_RSS => config.feed_all_rss,
         FEED_ALL_ATOM => config.feed_all_atom,
+        FEED_ALL_JSON => config.feed_all_json,
+        HIGHLIGHT_CSS => config.highlight_css,
         pages => pages,
     };
+    let base_fingerprint = cache::Fingerprint::default()
+        .add_serialized(&base_context)?
+        .finish();
 
-    let tmpl = jinja.get_template("index.html")?;
-    let index = tmpl.render(minijinja::context! {
-    articles_page =>  ArticlesPage{object_list: recent_articles.iter().take(10).cloned().collect()},
-    ..base_context.clone()})?;
-    std::fs::write(render_path.join("index.html"), index)?;
+    let fingerprint = posts_fingerprint(&recent_posts[..recent_posts.len().min(10)])?
+        .add(template_fingerprint("index.html")?)
+        .add(&base_fingerprint)
+        .finish();
+    let result = cache.write("index.html", &fingerprint, || {
+        let tmpl = jinja.get_template("index.html")?;
+        Ok(tmpl.render(minijinja::context! {
+        articles_page =>  ArticlesPage{object_list: recent_articles.iter().take(10).copied().collect()},
+        ..base_context.clone()})?)
+    });
+    report_render(&templates_path.join("index.html"), result);
 
-    let tmpl = jinja.get_template("archives.html")?;
-    let archives = tmpl.render(minijinja::context! {
-    dates => recent_articles,
-    ..base_context.clone()})?;
-    std::fs::write(render_path.join("archives.html"), archives)?;
+    let fingerprint = posts_fingerprint(&recent_posts)?
+        .add(template_fingerprint("archives.html")?)
+        .add(&base_fingerprint)
+        .finish();
+    let result = cache.write("archives.html", &fingerprint, || {
+        let tmpl = jinja.get_template("archives.html")?;
+        Ok(tmpl.render(minijinja::context! {
+        dates => recent_articles,
+        ..base_context.clone()})?)
+    });
+    report_render(&templates_path.join("archives.html"), result);
 
     let max_step = 5f32;
     let max_count = by_tag.values().map(|ps| ps.len()).max().unwrap_or(1) as f32;
@@ -426,43 +990,102 @@ async fn main() -> Result<()> {
         })
         .collect::<Vec<_>>();
 
-    let tmpl = jinja.get_template("tags.html")?;
-    let tags = tmpl.render(minijinja::context! {
-    tag_cloud => tag_counts,
-    ..base_context.clone()})?;
-    std::fs::write(render_path.join("tags.html"), tags)?;
+    let fingerprint = cache::Fingerprint::default()
+        .add_serialized(&tag_counts)?
+        .add(template_fingerprint("tags.html")?)
+        .add(&base_fingerprint)
+        .finish();
+    let result = cache.write("tags.html", &fingerprint, || {
+        let tmpl = jinja.get_template("tags.html")?;
+        Ok(tmpl.render(minijinja::context! {
+        tag_cloud => tag_counts,
+        ..base_context.clone()})?)
+    });
+    report_render(&templates_path.join("tags.html"), result);
 
-    for (tag, mut posts) in by_tag {
+    // Every failing tag is reported, not just the first one.
+    let results = by_tag.into_par_iter().map(|(tag, mut posts)| {
         posts.sort_by(|a, b| (&a.path, a.timestamp).cmp(&(&b.path, b.timestamp)));
-        let articles = posts
-            .iter()
-            .rev()
-            .take(10)
-            .map(|p| p.to_article())
-            .collect::<Result<_>>()?;
-        let tmpl = jinja.get_template("tag.html")?;
-        let tags = tmpl.render(minijinja::context! {
-        tag => tag,
-        articles_page => ArticlesPage{object_list: articles},
-        ..base_context.clone()})?;
-        let dst = render_path.join("tags").join(format!("{tag:}/index.html"));
-        std::fs::create_dir_all(dst.parent().unwrap())?;
-        std::fs::write(dst, tags)?;
-    }
-
-    render::feeds(&config, &recent_articles, &render_path)?;
+        posts.reverse();
+
+        let fingerprint = feed_fingerprint(&posts)?
+            .add(tag)
+            .add(&config.tag_feed_atom)
+            .add(&config.tag_feed_rss)
+            .add(&config.tag_feed_json)
+            .add(format!(
+                "{:?} {:?} {:?}",
+                config.tag_feed_atom_content,
+                config.tag_feed_rss_content,
+                config.tag_feed_json_content
+            ))
+            .finish();
+        let articles = all_ok(
+            posts
+                .iter()
+                .take(config.max_feed_entries)
+                .map(|p| p.article())
+                .collect(),
+        )?;
+        render::feeds(config, &cache, &fingerprint, Some(tag), &articles)?;
+
+        let posts = &posts[..posts.len().min(10)];
+        let tag_feed_url = render::tag_feed(&config.tag_feed_atom, tag);
+        let fingerprint = posts_fingerprint(posts)?
+            .add(tag)
+            .add(&tag_feed_url)
+            .add(template_fingerprint("tag.html")?)
+            .add(&base_fingerprint)
+            .finish();
+        let dst = Path::new("tags").join(format!("{tag:}/index.html"));
+        let result = cache.write(dst, &fingerprint, || {
+            let articles = posts.iter().map(|p| p.article()).collect::<Result<_>>()?;
+            let tmpl = jinja.get_template("tag.html")?;
+            Ok(tmpl.render(minijinja::context! {
+            tag => tag,
+            tag_feed_url => tag_feed_url,
+            articles_page => ArticlesPage{object_list: articles},
+            ..base_context.clone()})?)
+        });
+        report_render(&templates_path.join("tag.html"), result);
+        anyhow::Ok(())
+    });
+    all_ok(results.collect())?;
+
+    let fingerprint = feed_fingerprint(&recent_posts)?.finish();
+    render::feeds(config, &cache, &fingerprint, None, &recent_articles)?;
+
+    let fingerprint = cache::Fingerprint::default()
+        .add(&config.highlight_theme)
+        .finish();
+    cache.write(&config.highlight_css, &fingerprint, || {
+        highlight::css(&config.highlight_theme)
+    })?;
 
     // Run once to render and save.
-    for f in files.iter() {
-        match f {
-            RawFile::Content(c) => render_content(&c, render_path, &jinja, &base_context)?,
-            RawFile::Static(i) => {
-                let dst = render_path.join(&i.path);
-                std::fs::create_dir_all(dst.parent().unwrap())?;
-                std::fs::write(dst, &i.data)?;
-            }
-        }
-    }
+    all_ok(
+        files
+            .par_iter()
+            .map(|f| match f {
+                RawFile::Content(c) => {
+                    let fingerprint = cache::Fingerprint::default()
+                        .add(c.fingerprint()?)
+                        .add(template_fingerprint(&format!("{:}.html", c.layout()))?)
+                        .add(&base_fingerprint)
+                        .finish();
+                    let result = cache.write(c.output_path(), &fingerprint, || {
+                        render_content(c, &jinja, &base_context)
+                    });
+                    report_render(&c.source, result);
+                    Ok(())
+                }
+                RawFile::Static(i) => {
+                    let fingerprint = cache::Fingerprint::default().add(&i.data).finish();
+                    cache.write(&i.path, &fingerprint, || Ok(&i.data))
+                }
+            })
+            .collect(),
+    )?;
 
     // TODO(swj): How to best ignore dependencies checked into repos?
     // std::fs::write(
@@ -470,12 +1093,252 @@ async fn main() -> Result<()> {
     //     stats::github_languages(&config).await?,
     // )?;
 
-    // Run again to verify internal links.
-    for f in files.iter() {
-        if let RawFile::Content(c) = f {
-            c.validate_links(render_path)?;
+    cache.finish()?;
+
+    // Verify internal links against the rendered site. Links and images can also come from
+    // templates, so every rendered page is crawled as well, skipping what was already checked
+    // in the markdown.
+    let site = links::Site::new(render_path, &config.siteurl);
+    let content: Vec<&RawContent> = files
+        .iter()
+        .filter_map(|f| match f {
+            RawFile::Content(c) => Some(c.as_ref()),
+            RawFile::Static(_) => None,
+        })
+        .collect();
+    let mut broken_links: Vec<links::BrokenLink> = all_ok(
+        content
+            .par_iter()
+            .map(|c| c.validate_links(&site))
+            .collect(),
+    )?
+    .into_iter()
+    .flatten()
+    .collect();
+    let mut checked: HashMap<PathBuf, HashSet<&str>> = HashMap::new();
+    for c in content.iter() {
+        checked
+            .entry(c.output_path())
+            .or_default()
+            .extend(c.article()?.links.iter().map(|l| l.url.as_str()));
+    }
+    broken_links.extend(site.check_pages(&checked)?);
+    links::report(
+        broken_links,
+        config.strict,
+        options.link_report.as_deref(),
+        &diagnostics,
+    )?;
+
+    diagnostics.report()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+author = "Author"
+sitename = "Site"
+siteurl = "https://example.com"
+user_logo_url = ""
+content_path = "content"
+templates_path = "templates"
+feed_all_atom = "feeds/all.atom.xml"
+feed_all_rss = "feeds/all.rss.xml"
+max_feed_entries = 10
+github_user = ""
+github_access_token = ""
+"#;
+
+    fn write(path: &Path, contents: &str) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    fn post(title: &str, tag: &str, body: &str) -> String {
+        format!("title: {title}\ndate: 2024-01-02\nlayout: post\ntags: {tag}\n\n{body}\n")
+    }
+
+    /// A blog with the given posts, as (file name, contents).
+    fn blog(posts: &[(&str, &str)]) -> tempfile::TempDir {
+        let blog = tempfile::tempdir().unwrap();
+        write(&blog.path().join("config.toml"), CONFIG);
+        for (name, contents) in posts {
+            write(&blog.path().join("content").join(name), contents);
         }
+        let templates = blog.path().join("templates");
+        for name in ["index", "archives", "tags", "tag"] {
+            write(&templates.join(format!("{name}.html")), name);
+        }
+        write(&templates.join("post.html"), "{{ article.content }}");
+        write(&templates.join("base.html"), "base");
+        blog
+    }
+
+    /// Builds a small blog, marks every output, changes `file` and builds again. Returns the
+    /// outputs that were written by the second build.
+    fn rebuilt_after(file: &str, contents: &str) -> Vec<String> {
+        let blog = blog(&[
+            ("a.md", &post("First", "one", "Hello.")),
+            ("b.md", &post("Second", "two", "World.")),
+        ]);
+        let output = tempfile::tempdir().unwrap();
+        let config = load_config(blog.path(), None, false).unwrap();
+        let options = BuildOptions::default();
+        build(blog.path(), &config, output.path(), &options).unwrap();
+
+        let outputs: Vec<PathBuf> = glob::glob(output.path().join("**/*").to_str().unwrap())
+            .unwrap()
+            .map(|p| p.unwrap())
+            .filter(|p| p.is_file() && !p.ends_with(".kaihan-cache.json"))
+            .collect();
+        for p in outputs.iter() {
+            fs::write(p, "unchanged").unwrap();
+        }
+
+        write(&blog.path().join(file), contents);
+        build(blog.path(), &config, output.path(), &options).unwrap();
+        let mut rebuilt: Vec<String> = outputs
+            .iter()
+            .filter(|p| fs::read_to_string(p).unwrap() != "unchanged")
+            .map(|p| {
+                p.strip_prefix(output.path())
+                    .unwrap()
+                    .to_string_lossy()
+                    .into_owned()
+            })
+            .collect();
+        rebuilt.sort();
+        rebuilt
+    }
+
+    #[test]
+    fn changed_post_rerenders_its_outputs() {
+        assert_eq!(
+            rebuilt_after("content/b.md", &post("Second", "two", "Everyone.")),
+            [
+                "archives.html",
+                "blog/2024/01/02/second/index.html",
+                "feeds/all.atom.xml",
+                "feeds/all.json",
+                "feeds/all.rss.xml",
+                "index.html",
+                "tags/two/feed.atom.xml",
+                "tags/two/feed.json",
+                "tags/two/feed.rss.xml",
+                "tags/two/index.html",
+            ]
+        );
+    }
+
+    #[test]
+    fn changed_template_rerenders_its_outputs() {
+        assert_eq!(
+            rebuilt_after("templates/tag.html", "tag!"),
+            ["tags/one/index.html", "tags/two/index.html",]
+        );
+        assert_eq!(
+            rebuilt_after("templates/base.html", "unused"),
+            Vec::<String>::new()
+        );
     }
 
-    Ok(())
+    #[test]
+    fn per_host_must_be_positive() {
+        let flags = |n| {
+            Flags::try_parse_from(["kaihan", "--input", ".", "check-external", "--per-host", n])
+        };
+        assert!(flags("0").is_err());
+        assert!(matches!(
+            flags("1").unwrap().command,
+            Some(Command::CheckExternal { per_host: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn only_links_to_existing_snapshots() {
+        let archived = "https://example.org/kept";
+        let blog = blog(&[(
+            "a.md",
+            &post(
+                "First",
+                "one",
+                &format!("[kept](!{archived}) and [lost](!http://127.0.0.1:1/lost)"),
+            ),
+        )]);
+        let snapshot = archive::snapshot_url(archived);
+        write(
+            &blog
+                .path()
+                .join(format!("{snapshot}index.html").trim_start_matches('/')),
+            "snapshot",
+        );
+        write(
+            &blog.path().join("archive/manifest.json"),
+            &format!(
+                r#"{{"{archived}": {{"dir": "{}", "files": ["index.html"], "archived_at": "2024-01-02T00:00:00Z"}}}}"#,
+                snapshot.trim_matches('/').trim_start_matches("archive/")
+            ),
+        );
+
+        let output = tempfile::tempdir().unwrap();
+        let config = load_config(blog.path(), None, false).unwrap();
+        for offline in [false, true] {
+            let options = BuildOptions {
+                offline,
+                ..Default::default()
+            };
+            build(blog.path(), &config, output.path(), &options).unwrap();
+            let html =
+                fs::read_to_string(output.path().join("blog/2024/01/02/first/index.html")).unwrap();
+            assert_eq!(
+                html.matches(r#"class="archived-copy""#).count(),
+                1,
+                "{html}"
+            );
+            assert!(html.contains(&format!(r#"href="{snapshot}""#)), "{html}");
+            assert!(output
+                .path()
+                .join(&snapshot[1..])
+                .join("index.html")
+                .is_file());
+        }
+    }
+
+    #[test]
+    fn summary_errors_point_at_the_metadata() {
+        let contents = "title: A\ndate: 2024-01-02\nsummary: Sum $\\frac{a}$\n\nBody.\n";
+        let content = read_content(Path::new("a.md"), contents, &Diagnostics::default()).unwrap();
+        let e = content.article().unwrap_err();
+        let e = e.downcast_ref::<frontmatter::Error>().unwrap();
+        assert_eq!(e.line, 3);
+        assert!(e.message.contains("missing argument"), "{e}");
+    }
+
+    #[test]
+    fn json_feeds_cant_have_both() {
+        let blog = tempfile::tempdir().unwrap();
+        let config = format!("{CONFIG}tag_feed_json_content = \"both\"\n");
+        write(&blog.path().join("config.toml"), &config);
+        let e = load_config(blog.path(), None, false).unwrap_err();
+        assert!(e.to_string().contains("`tag_feed_json_content`"), "{e}");
+    }
+
+    #[test]
+    fn descriptions_are_escaped_for_templates() {
+        let contents = "title: A\ndate: 2024-01-02\ndescription: Why <b> & \"quotes\"\n\nBody.\n";
+        let content = read_content(Path::new("a.md"), contents, &Diagnostics::default()).unwrap();
+        let article = content.article().unwrap();
+        assert_eq!(article.description, r#"Why <b> & "quotes""#);
+        assert_eq!(
+            minijinja::Environment::new()
+                .render_str(
+                    "{{ article.description_html }}",
+                    minijinja::context! { article }
+                )
+                .unwrap(),
+            "Why &lt;b&gt; &amp; &quot;quotes&quot;"
+        );
+    }
 }
@@ -1,79 +1,1291 @@
-use anyhow::Result;
-use pulldown_cmark::{CowStr, Event, Tag, TagEnd};
+use crate::bibliography::Bibliography;
+use anyhow::{anyhow, bail, Result};
+use pulldown_cmark::{CodeBlockKind, CowStr, Event, Tag, TagEnd};
+use serde::{Deserialize, Serialize};
 
+use std::collections::{HashMap, HashSet};
 use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
 
-pub fn to_events(markdown: &str) -> Result<Vec<Event>> {
-    let mut options = pulldown_cmark::Options::empty();
-    options.insert(pulldown_cmark::Options::ENABLE_TABLES);
-    options.insert(pulldown_cmark::Options::ENABLE_MATH);
-    options.insert(pulldown_cmark::Options::ENABLE_FOOTNOTES);
-
-    let parser = pulldown_cmark::Parser::new_ext(&markdown, options);
-
-    let parser = parser.map(|e| match e {
-        Event::Start(pulldown_cmark::Tag::Link {
-            link_type,
-            dest_url,
-            title,
-            id,
-        }) => {
-            // TODO(swj): Support link archiving.
-            let url = dest_url.trim_start_matches("!").to_owned();
-
-            Event::Start(pulldown_cmark::Tag::Link {
+/// Everything derived from a single pass over the markdown of a post.
+pub struct Analysis {
+    pub html: String,
+    pub summary: String,
+    pub description: String,
+    pub links: Vec<Link>,
+    pub headings: Vec<Heading>,
+    pub toc: Vec<TocEntry>,
+    // Files included into code blocks.
+    pub includes: Vec<PathBuf>,
+    // Problems that don't keep the post from being rendered, like unknown code block attributes.
+    pub warnings: Vec<Error>,
+}
+
+/// A problem with the markdown, `line` is relative to its start, starting at 1.
+#[derive(Debug, Clone)]
+pub struct Error {
+    pub line: usize,
+    pub message: String,
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {:}: {:}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for Error {}
+
+struct Parsed<'a> {
+    events: Vec<Event<'a>>,
+    summary: String,
+    description: String,
+    links: Vec<Link>,
+    headings: Vec<Heading>,
+    includes: Vec<PathBuf>,
+    warnings: Vec<Error>,
+}
+
+/// Destination of a link or image, and the line of the markdown it appears on (starting at 1).
+#[derive(Debug, Clone)]
+pub struct Link {
+    pub url: String,
+    pub line: usize,
+    pub image: bool,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Heading {
+    pub level: usize,
+    pub id: Option<String>,
+    pub title: String,
+}
+
+/// A heading with all headings below it, for tables of contents.
+#[derive(Serialize, Debug, Clone)]
+pub struct TocEntry {
+    pub level: usize,
+    pub id: String,
+    pub title: String,
+    pub children: Vec<TocEntry>,
+}
+
+/// How to render the markdown of a post.
+#[derive(Clone, Copy)]
+pub struct Options<'a> {
+    // Where the post is, which files are included relative to.
+    pub dir: &'a Path,
+    // Where citations are looked up.
+    pub bibliography: Option<&'a Bibliography>,
+    // Links marked for archiving which have a snapshot to link to, see `archive_links`.
+    pub archived: &'a [String],
+    pub footnotes: FootnoteStyle,
+    // Length of summaries without a `<!-- more -->` marker.
+    pub summary_words: usize,
+}
+
+/// Where footnotes are shown, set per site or per post with `footnotes`.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FootnoteStyle {
+    // A numbered list at the end of the post.
+    #[default]
+    Bottom,
+    // Margin notes right next to where they are referenced.
+    Sidenotes,
+}
+
+impl TryFrom<&str> for FootnoteStyle {
+    type Error = anyhow::Error;
+
+    fn try_from(s: &str) -> Result<FootnoteStyle> {
+        match s.to_ascii_lowercase().as_str() {
+            "bottom" => Ok(FootnoteStyle::Bottom),
+            "sidenotes" => Ok(FootnoteStyle::Sidenotes),
+            _ => bail!(
+                "unknown footnote style {:}, expected bottom or sidenotes",
+                s
+            ),
+        }
+    }
+}
+
+/// Analyzes the `markdown` of a post.
+pub fn analyze(markdown: &str, options: &Options) -> Result<Analysis> {
+    let parsed = parse(markdown, options)?;
+
+    Ok(Analysis {
+        html: to_html(parsed.events),
+        summary: parsed.summary,
+        description: parsed.description,
+        links: parsed.links,
+        toc: toc(&parsed.headings),
+        headings: parsed.headings,
+        includes: parsed.includes,
+        warnings: parsed.warnings,
+    })
+}
+
+pub fn to_events<'a>(markdown: &'a str, options: &Options) -> Result<Vec<Event<'a>>> {
+    Ok(parse(markdown, options)?.events)
+}
+
+pub fn to_html(events: Vec<Event>) -> String {
+    let mut html = String::new();
+    pulldown_cmark::html::push_html(&mut html, events.into_iter());
+    html
+}
+
+fn extensions() -> pulldown_cmark::Options {
+    let mut extensions = pulldown_cmark::Options::empty();
+    extensions.insert(pulldown_cmark::Options::ENABLE_TABLES);
+    extensions.insert(pulldown_cmark::Options::ENABLE_MATH);
+    extensions.insert(pulldown_cmark::Options::ENABLE_FOOTNOTES);
+    extensions
+}
+
+fn line_starts(markdown: &str) -> Vec<usize> {
+    std::iter::once(0)
+        .chain(markdown.match_indices('\n').map(|(i, _)| i + 1))
+        .collect()
+}
+
+/// Links marked with a leading `!` to keep a copy in case they disappear, see `archive`, with
+/// the line they are on (starting at 1). Found before the rest of the post is analyzed, so that
+/// posts only link to snapshots that exist.
+pub fn archive_links(markdown: &str) -> Vec<(String, usize)> {
+    let line_starts = line_starts(markdown);
+    pulldown_cmark::Parser::new_ext(markdown, extensions())
+        .into_offset_iter()
+        .filter_map(|(e, range)| match e {
+            Event::Start(Tag::Link { dest_url, .. }) => Some((
+                dest_url.strip_prefix('!')?.to_owned(),
+                line_starts.partition_point(|&s| s <= range.start),
+            )),
+            _ => None,
+        })
+        .collect()
+}
+
+fn parse<'a>(markdown: &'a str, options: &Options) -> Result<Parsed<'a>> {
+    // Merged, so that labels and references aren't split up at characters like `_`.
+    let parser = pulldown_cmark::TextMergeWithOffset::new(
+        pulldown_cmark::Parser::new_ext(markdown, extensions()).into_offset_iter(),
+    );
+
+    let line_starts = line_starts(markdown);
+    let mut links = Vec::new();
+    let mut events = Vec::new();
+    // Destination of the current link, if it has a snapshot.
+    let mut archived = None;
+    // Line of every fenced code block.
+    let mut code_lines = Vec::new();
+    // MathML of every formula, see `Formulas`.
+    let mut formulas = Formulas::new();
+    // Line of every heading.
+    let mut heading_lines = Vec::new();
+    let mut labels = Labels::default();
+    let mut citations = Citations {
+        bibliography: options.bibliography,
+        cited: Vec::new(),
+    };
+    let mut in_code = false;
+    let mut warnings = Vec::new();
+
+    for (e, range) in parser {
+        let line = line_starts.partition_point(|&s| s <= range.start);
+        match e {
+            Event::Start(Tag::CodeBlock(_)) => in_code = true,
+            Event::End(TagEnd::CodeBlock) => in_code = false,
+            _ => {}
+        }
+        match e {
+            Event::Start(Tag::Link {
                 link_type,
-                dest_url: pulldown_cmark::CowStr::Boxed(url.into_boxed_str()),
+                dest_url,
                 title,
                 id,
+            }) => {
+                // Links starting with `!` are archived, see `archive_links`.
+                let url = dest_url.strip_prefix('!').unwrap_or(&dest_url).to_owned();
+                links.push(Link {
+                    url: url.clone(),
+                    line,
+                    image: false,
+                });
+                if dest_url.starts_with('!') && options.archived.contains(&url) {
+                    archived = Some(url.clone());
+                }
+
+                events.push(Event::Start(Tag::Link {
+                    link_type,
+                    dest_url: CowStr::Boxed(url.into_boxed_str()),
+                    title,
+                    id,
+                }));
+            }
+            Event::End(TagEnd::Link) => {
+                events.push(e);
+                if let Some(url) = archived.take() {
+                    events.push(Event::InlineHtml(
+                        format!(
+                            r#" <a class="archived-copy" href="{:}">(archived copy)</a>"#,
+                            crate::archive::snapshot_url(&url)
+                        )
+                        .into(),
+                    ));
+                }
+            }
+            Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(_))) => {
+                code_lines.push(line);
+                events.push(e);
+            }
+            Event::InlineMath(ref tex) | Event::DisplayMath(ref tex) => {
+                let display = matches!(e, Event::DisplayMath(_));
+                formulas
+                    .entry((tex.to_string(), display))
+                    .or_insert_with(|| match crate::math::to_mathml(tex, display) {
+                        Ok(mathml) => Ok(Some(mathml)),
+                        Err(e) => match e.downcast_ref::<crate::math::Unsupported>() {
+                            Some(unsupported) => {
+                                warnings.push(Error {
+                                    line,
+                                    message: format!("{unsupported:}, leaving the formula to be rendered in the browser"),
+                                });
+                                Ok(None)
+                            }
+                            None => Err(Error {
+                                line,
+                                message: format!("invalid math: {e:#}"),
+                            }),
+                        },
+                    });
+                events.push(e);
+            }
+            Event::Start(Tag::Heading { .. }) => {
+                heading_lines.push(line);
+                events.push(e);
+            }
+            Event::End(TagEnd::Heading(_)) => {
+                explicit_heading_id(&mut events);
+                events.push(e);
+            }
+            Event::Start(Tag::Image { ref dest_url, .. }) => {
+                links.push(Link {
+                    url: dest_url.to_string(),
+                    line,
+                    image: true,
+                });
+                events.push(e);
+            }
+            Event::Text(text) if !in_code => {
+                let error = |message| Error { line, message };
+                let text = match labels.label_previous(&mut events, &text).map_err(error)? {
+                    Some(rest) => CowStr::from(rest.to_owned()),
+                    None => text,
+                };
+                for event in citations.cite(text).map_err(error)? {
+                    if let Event::Text(text) = &event {
+                        for r in references(text) {
+                            links.push(Link {
+                                url: format!("#{:}", &text[r.start + 1..r.end]),
+                                line,
+                                image: false,
+                            });
+                        }
+                    }
+                    events.push(event);
+                }
+            }
+            Event::End(TagEnd::Paragraph) => {
+                if !labels
+                    .caption_table(&mut events)
+                    .map_err(|message| Error { line, message })?
+                {
+                    events.push(e);
+                }
+            }
+            _ => events.push(e),
+        }
+    }
+
+    let events = labels.resolve(events);
+    let events = heading_ids(events, &heading_lines)?;
+    // Before formulas are replaced, so that their TeX ends up in the titles.
+    let headings = headings(&events);
+    let events = toc_marker(events, &headings);
+    // Also before formulas are replaced, they are left out of it.
+    let description = description(&events);
+    let events = math(events, &formulas)?;
+    let (events, includes) = code_blocks(events, &code_lines, options.dir, &mut warnings)?;
+    // While footnote references and definitions are still easy to tell apart.
+    let summary = summary(&events, options.summary_words);
+    let mut events = footnotes(events, options.footnotes);
+    events.extend(citations.references());
+    Ok(Parsed {
+        events,
+        summary,
+        description,
+        links,
+        headings,
+        includes,
+        warnings,
+    })
+}
+
+/// Marks the end of the summary, see `summary`.
+fn is_more_marker(event: &Event) -> bool {
+    matches!(event, Event::Html(h) | Event::InlineHtml(h) if h.trim() == "<!-- more -->")
+}
+
+/// The beginning of a post as HTML: everything before a `<!-- more -->` marker, or else about
+/// the first `words` words. Open tags are closed again, headings, footnotes, citations and
+/// tables of contents are left out.
+fn summary(events: &[Event], words: usize) -> String {
+    let has_marker = events.iter().any(is_more_marker);
+    let mut summary = Vec::new();
+    // Ends of all tags currently open.
+    let mut open = Vec::new();
+    // End of the heading or footnote currently left out.
+    let mut skipping: Option<TagEnd> = None;
+    // Where the current top-level block starts in `summary`.
+    let mut block_start = 0;
+    let mut count = 0;
+
+    for e in events {
+        if let Some(end) = &skipping {
+            if matches!(e, Event::End(t) if t == end) {
+                skipping = None;
+            }
+            continue;
+        }
+        if is_more_marker(e) {
+            break;
+        }
+        match e {
+            Event::Start(t @ (Tag::Heading { .. } | Tag::FootnoteDefinition(_))) => {
+                skipping = Some(t.to_end());
+            }
+            Event::FootnoteReference(_) => {}
+            Event::Html(h) | Event::InlineHtml(h)
+                if h.starts_with(r#"<nav class="toc">"#)
+                    || h.starts_with(r#"<span class="citation">"#) => {}
+            // Rather end with a whole block than start one that would be cut off right away.
+            Event::Start(_) if !has_marker && open.is_empty() && count >= words => break,
+            Event::Start(t) => {
+                if open.is_empty() {
+                    block_start = summary.len();
+                }
+                open.push(t.to_end());
+                summary.push(e.clone());
+            }
+            Event::End(_) => {
+                open.pop();
+                summary.push(e.clone());
+            }
+            Event::Text(t) if !has_marker => {
+                let is_word = |w: &str| w.chars().any(char::is_alphanumeric);
+                let n = t.split_whitespace().filter(|w| is_word(w)).count();
+                if count + n <= words {
+                    count += n;
+                    summary.push(e.clone());
+                    continue;
+                }
+                // Code can't be cut off in the middle, leave out the whole block instead.
+                if open.contains(&TagEnd::CodeBlock) {
+                    summary.truncate(block_start);
+                    open.clear();
+                    break;
+                }
+                let mut cut = String::new();
+                for w in t.split_inclusive(char::is_whitespace) {
+                    if is_word(w) {
+                        if count == words {
+                            break;
+                        }
+                        count += 1;
+                    }
+                    cut.push_str(w);
+                }
+                summary.push(Event::Text(format!("{:}…", cut.trim_end()).into()));
+                break;
+            }
+            _ => summary.push(e.clone()),
+        }
+    }
+
+    summary.extend(open.into_iter().rev().map(Event::End));
+    to_html(summary)
+}
+
+/// Makes the `summary` of the post at the absolute `url` work on other pages: links within the
+/// post point at the post instead, and ids are dropped so that they can't clash with those of
+/// the page.
+pub fn detach_summary(summary: &str, url: &str) -> Result<String> {
+    let html = lol_html::rewrite_str(
+        summary,
+        lol_html::RewriteStrSettings {
+            element_content_handlers: vec![
+                lol_html::element!("[href^='#']", |el| {
+                    let href = el.get_attribute("href").unwrap_or_default();
+                    el.set_attribute("href", &format!("{:}{:}", url, href))?;
+                    Ok(())
+                }),
+                lol_html::element!("[id]", |el| {
+                    el.remove_attribute("id");
+                    Ok(())
+                }),
+            ],
+            ..lol_html::RewriteStrSettings::new()
+        },
+    )?;
+    Ok(html)
+}
+
+/// Longest description, in characters. Search engines cut off longer ones.
+const DESCRIPTION_LENGTH: usize = 160;
+
+/// The beginning of a post as plain text, for `<meta name="description">` and the like: as many
+/// whole sentences as fit into `DESCRIPTION_LENGTH`. Markup, math, code blocks, images,
+/// headings and footnotes are left out.
+fn description(events: &[Event]) -> String {
+    let mut text = String::new();
+    // End of the element currently left out.
+    let mut skipping: Option<TagEnd> = None;
+
+    for e in events {
+        if let Some(end) = &skipping {
+            if matches!(e, Event::End(t) if t == end) {
+                skipping = None;
+            }
+            continue;
+        }
+        if is_more_marker(e) || text.chars().count() > DESCRIPTION_LENGTH {
+            break;
+        }
+        match e {
+            Event::Start(
+                t @ (Tag::Heading { .. }
+                | Tag::FootnoteDefinition(_)
+                | Tag::CodeBlock(_)
+                | Tag::Image { .. }),
+            ) => skipping = Some(t.to_end()),
+            Event::Text(t) | Event::Code(t) => {
+                // Where math or a citation was left out right before punctuation.
+                if t.starts_with(['.', ',', ';', ':', '!', '?', ')']) {
+                    text.truncate(text.trim_end().len());
+                }
+                text.push_str(t);
+            }
+            Event::SoftBreak
+            | Event::HardBreak
+            | Event::End(TagEnd::Paragraph | TagEnd::Item | TagEnd::TableCell) => text.push(' '),
+            _ => {}
+        }
+    }
+
+    let mut description = String::new();
+    // Length of `description` after the last whole sentence.
+    let mut sentences = 0;
+    let mut complete = true;
+    for w in text.split_whitespace() {
+        if description.chars().count() + w.chars().count() + 1 > DESCRIPTION_LENGTH {
+            complete = false;
+            break;
+        }
+        if !description.is_empty() {
+            description.push(' ');
+        }
+        description.push_str(w);
+        if w.ends_with(['.', '!', '?']) {
+            sentences = description.len();
+        }
+    }
+    if complete {
+        description
+    } else if sentences > 0 {
+        description[..sentences].to_owned()
+    } else {
+        format!("{:}…", description.trim_end_matches([',', ';', ':']))
+    }
+}
+
+/// Display math, images and tables labeled like `{#eq:loss}`, numbered in order of appearance
+/// per kind. References like `@eq:loss` link to them, showing their number.
+#[derive(Default)]
+struct Labels {
+    numbers: HashMap<String, (Kind, usize)>,
+    counts: HashMap<Kind, usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Kind {
+    Equation,
+    Figure,
+    Table,
+}
+
+impl Kind {
+    fn prefix(self) -> &'static str {
+        match self {
+            Kind::Equation => "eq:",
+            Kind::Figure => "fig:",
+            Kind::Table => "tbl:",
+        }
+    }
+
+    /// How references to number `n` of this kind are shown.
+    fn name(self, n: usize) -> String {
+        match self {
+            Kind::Equation => format!("Eq. ({n:})"),
+            Kind::Figure => format!("Figure {n:}"),
+            Kind::Table => format!("Table {n:}"),
+        }
+    }
+}
+
+impl Labels {
+    fn add(&mut self, label: &str, kind: Kind) -> Result<usize, String> {
+        if !label.starts_with(kind.prefix()) {
+            return Err(format!(
+                "label {label:?} should start with {:?}",
+                kind.prefix()
+            ));
+        }
+        if self.numbers.contains_key(label) {
+            return Err(format!("label {label:?} is used more than once"));
+        }
+        let count = self.counts.entry(kind).or_default();
+        *count += 1;
+        self.numbers.insert(label.to_owned(), (kind, *count));
+        Ok(*count)
+    }
+
+    /// Labels the display math or image just before `text`, if `text` starts with a label like
+    /// `{#eq:loss}`. Returns the rest of `text`.
+    fn label_previous<'t>(
+        &mut self,
+        events: &mut Vec<Event>,
+        text: &'t str,
+    ) -> Result<Option<&'t str>, String> {
+        let kind = match events.last() {
+            Some(Event::DisplayMath(_)) => Kind::Equation,
+            Some(Event::End(TagEnd::Image)) => Kind::Figure,
+            _ => return Ok(None),
+        };
+        let Some((label, rest)) = split_label(text.trim_start()) else {
+            return Ok(None);
+        };
+        let n = self.add(label, kind)?;
+
+        if kind == Kind::Equation {
+            events.insert(
+                events.len() - 1,
+                Event::InlineHtml(format!(r#"<span class="equation" id="{label:}">"#).into()),
+            );
+            events.push(Event::InlineHtml(
+                format!(r#"<span class="equation-number">({n:})</span></span>"#).into(),
+            ));
+        } else {
+            let start = events
+                .iter()
+                .rposition(|e| matches!(e, Event::Start(Tag::Image { .. })))
+                .unwrap();
+            let alt: Vec<Event> = events[start + 1..events.len() - 1].to_vec();
+            events.insert(
+                start,
+                Event::InlineHtml(format!(r#"<span class="figure" id="{label:}">"#).into()),
+            );
+            events.push(Event::InlineHtml(
+                format!(r#"<span class="caption">{:}: "#, kind.name(n)).into(),
+            ));
+            events.extend(alt);
+            events.push(Event::InlineHtml("</span></span>".into()));
+        }
+        Ok(Some(rest))
+    }
+
+    /// Turns a paragraph like `: Results per model {#tbl:results}` directly after a table into
+    /// its caption, numbering the table if it's labeled. Called at the end of every paragraph,
+    /// returns whether it was a caption.
+    fn caption_table(&mut self, events: &mut Vec<Event>) -> Result<bool, String> {
+        let [.., Event::End(TagEnd::Table), Event::Start(Tag::Paragraph), Event::Text(text)] =
+            events.as_slice()
+        else {
+            return Ok(false);
+        };
+        let Some(caption) = text.strip_prefix(':') else {
+            return Ok(false);
+        };
+        let caption = caption.trim();
+        let (caption, label) = match caption.rfind("{#") {
+            Some(i) => match split_label(&caption[i..]) {
+                Some((label, "")) => (caption[..i].trim().to_owned(), Some(label.to_owned())),
+                _ => (caption.to_owned(), None),
+            },
+            None => (caption.to_owned(), None),
+        };
+        events.truncate(events.len() - 2);
+
+        let start = events
+            .iter()
+            .rposition(|e| matches!(e, Event::Start(Tag::Table(_))))
+            .unwrap();
+        let (open, prefix) = match &label {
+            Some(label) => (
+                format!("<figure class=\"table\" id=\"{label:}\">\n"),
+                format!("{:}: ", Kind::Table.name(self.add(label, Kind::Table)?)),
+            ),
+            None => ("<figure class=\"table\">\n".to_owned(), String::new()),
+        };
+        events.insert(start, Event::Html(open.into()));
+        events.push(Event::Html("<figcaption>".into()));
+        events.push(Event::Text(format!("{prefix:}{caption:}").into()));
+        events.push(Event::Html("</figcaption>\n</figure>\n".into()));
+        Ok(true)
+    }
+
+    /// Replaces references like `@eq:loss` by links to what they refer to. References to
+    /// unknown labels still link to them, for the link checker to report.
+    fn resolve<'a>(&self, events: Vec<Event<'a>>) -> Vec<Event<'a>> {
+        let mut new_events = Vec::with_capacity(events.len());
+        let mut in_code = false;
+        for event in events {
+            match event {
+                Event::Start(Tag::CodeBlock(_)) => in_code = true,
+                Event::End(TagEnd::CodeBlock) => in_code = false,
+                _ => {}
+            }
+            let text = match event {
+                Event::Text(ref t) if !in_code => t,
+                _ => {
+                    new_events.push(event);
+                    continue;
+                }
+            };
+            let mut last = 0;
+            for r in references(text) {
+                let label = &text[r.start + 1..r.end];
+                let name = match self.numbers.get(label) {
+                    Some((kind, n)) => kind.name(*n),
+                    None => label.to_owned(),
+                };
+                new_events.push(Event::Text(text[last..r.start].to_owned().into()));
+                new_events.push(Event::Start(Tag::Link {
+                    link_type: pulldown_cmark::LinkType::Inline,
+                    dest_url: format!("#{label:}").into(),
+                    title: "".into(),
+                    id: "".into(),
+                }));
+                new_events.push(Event::Text(name.into()));
+                new_events.push(Event::End(TagEnd::Link));
+                last = r.end;
+            }
+            if last == 0 {
+                new_events.push(event);
+            } else if last < text.len() {
+                new_events.push(Event::Text(text[last..].to_owned().into()));
+            }
+        }
+        new_events
+    }
+}
+
+/// Citations like `[@key]` or `[@one, p. 3; @two]`, numbered in order of first citation.
+struct Citations<'b> {
+    bibliography: Option<&'b Bibliography>,
+    // Keys of all cited entries, in order.
+    cited: Vec<String>,
+}
+
+impl Citations<'_> {
+    /// Replaces all citations in `text` by links to the references, which `references` adds to
+    /// the end of the post. Posts without a bibliography don't cite anything, text like
+    /// `[@someone]` is left alone there.
+    fn cite<'a>(&mut self, text: CowStr<'a>) -> Result<Vec<Event<'a>>, String> {
+        let Some(bibliography) = self.bibliography else {
+            return Ok(vec![Event::Text(text)]);
+        };
+        let mut events = Vec::new();
+        let mut last = 0;
+        for Citation { range, items } in citation_spans(&text) {
+            let (start, end) = (range.start, range.end);
+            if start > last {
+                events.push(Event::Text(text[last..start].to_owned().into()));
+            }
+            // A single event, so that summaries can leave citations out, see `summary`.
+            let mut html = String::from(r#"<span class="citation">["#);
+            let has_locators = items.iter().any(|(_, locator)| !locator.is_empty());
+            for (i, (key, locator)) in items.into_iter().enumerate() {
+                if !bibliography.contains(key) {
+                    return Err(format!("unknown citation key {key:?}"));
+                }
+                let n = match self.cited.iter().position(|k| k == key) {
+                    Some(i) => i + 1,
+                    None => {
+                        self.cited.push(key.to_owned());
+                        self.cited.len()
+                    }
+                };
+                // Locators would be ambiguous between commas.
+                let separator = match i {
+                    0 => "",
+                    _ if has_locators => "; ",
+                    _ => ", ",
+                };
+                write!(html, r##"{separator}<a href="#ref-{key}">{n}</a>"##).unwrap();
+                if !locator.is_empty() {
+                    write!(html, ", {}", crate::highlight::escape_html(locator)).unwrap();
+                }
+            }
+            html.push_str("]</span>");
+            events.push(Event::InlineHtml(html.into()));
+            last = end;
+        }
+
+        if last == 0 {
+            events.push(Event::Text(text));
+        } else if last < text.len() {
+            events.push(Event::Text(text[last..].to_owned().into()));
+        }
+        Ok(events)
+    }
+
+    /// The list of all cited entries.
+    fn references(&self) -> Vec<Event<'static>> {
+        let Some(bibliography) = self.bibliography.filter(|_| !self.cited.is_empty()) else {
+            return vec![];
+        };
+        let mut html = String::from(
+            "<section class=\"references\">\n<h2>References</h2>\n<ol class=\"references-list\">\n",
+        );
+        for key in self.cited.iter() {
+            writeln!(
+                html,
+                r#"<li id="ref-{key:}">{}</li>"#,
+                bibliography.format(key)
+            )
+            .unwrap();
+        }
+        html.push_str("</ol>\n</section>\n");
+        vec![Event::Html(html.into())]
+    }
+}
+
+fn is_citation_key_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "_-:./+".contains(c)
+}
+
+/// A citation like `[@one, p. 3; @two]`, within some text.
+struct Citation<'t> {
+    range: std::ops::Range<usize>,
+    // Cited keys, and their locators like `p. 3`.
+    items: Vec<(&'t str, &'t str)>,
+}
+
+fn citation_spans(text: &str) -> Vec<Citation<'_>> {
+    let mut spans = Vec::new();
+    for (start, _) in text.match_indices("[@") {
+        let Some(len) = text[start..].find(']') else {
+            continue;
+        };
+        let inner = &text[start + 1..start + len];
+        let items: Option<Vec<(&str, &str)>> = inner
+            .split(';')
+            .map(|item| {
+                let item = item.trim().strip_prefix('@')?;
+                let key_len = item
+                    .find(|c| !is_citation_key_char(c))
+                    .unwrap_or(item.len());
+                let (key, locator) = item.split_at(key_len);
+                let locator = locator.trim_start().strip_prefix(',').unwrap_or(locator);
+                (!key.is_empty()).then_some((key, locator.trim()))
             })
+            .collect();
+        match items {
+            // References to labels in brackets are left alone, see `references`.
+            Some(items)
+                if !items.iter().any(|(key, _)| {
+                    [Kind::Equation, Kind::Figure, Kind::Table]
+                        .iter()
+                        .any(|k| key.starts_with(k.prefix()))
+                }) =>
+            {
+                spans.push(Citation {
+                    range: start..start + len + 1,
+                    items,
+                })
+            }
+            _ => {}
         }
-        _ => e,
-    });
+    }
+    spans
+}
+
+fn is_label_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "_-:.".contains(c)
+}
+
+/// Splits `{#eq:loss} rest` into the label and the rest.
+fn split_label(text: &str) -> Option<(&str, &str)> {
+    let (label, rest) = text.strip_prefix("{#")?.split_once('}')?;
+    (!label.is_empty() && label.chars().all(is_label_char)).then_some((label, rest))
+}
 
-    Ok(bottom_footnotes(parser.collect()))
+/// Byte ranges of all references like `@eq:loss` in `text`, including the `@`.
+fn references(text: &str) -> Vec<std::ops::Range<usize>> {
+    let mut references = Vec::new();
+    for (i, _) in text.match_indices('@') {
+        // Not part of an email address or handle.
+        if text[..i]
+            .chars()
+            .next_back()
+            .is_some_and(|c| c.is_alphanumeric())
+        {
+            continue;
+        }
+        let rest = &text[i + 1..];
+        if ![Kind::Equation, Kind::Figure, Kind::Table]
+            .iter()
+            .any(|k| rest.starts_with(k.prefix()))
+        {
+            continue;
+        }
+        let len = rest.find(|c| !is_label_char(c)).unwrap_or(rest.len());
+        // Punctuation ending a sentence isn't part of the label.
+        let label = rest[..len].trim_end_matches(['.', ':', '-']);
+        if label.contains(':') {
+            references.push(i..i + 1 + label.len());
+        }
+    }
+    references
+}
+
+/// MathML of every formula by its TeX and whether it is display math, converted while parsing
+/// so that errors are reported on the line the formula first appears on. Formulas can show up
+/// more than once later on, like in both the alt text and the caption of a figure. `None` for
+/// formulas using TeX that isn't supported, which are left to be rendered in the browser.
+type Formulas = HashMap<(String, bool), Result<Option<String>, Error>>;
+
+/// Replaces all formulas by their MathML from `formulas`, see `math::to_mathml`. Alt texts of
+/// images can't contain markup, they keep the TeX instead.
+fn math<'a>(events: Vec<Event<'a>>, formulas: &Formulas) -> Result<Vec<Event<'a>>> {
+    let mut in_image = false;
+    events
+        .into_iter()
+        .map(|event| {
+            let (tex, display) = match &event {
+                Event::Start(Tag::Image { .. }) => {
+                    in_image = true;
+                    return Ok(event);
+                }
+                Event::End(TagEnd::Image) => {
+                    in_image = false;
+                    return Ok(event);
+                }
+                Event::InlineMath(tex) | Event::DisplayMath(tex) if in_image => {
+                    return Ok(Event::Text(tex.clone()));
+                }
+                Event::InlineMath(tex) => (tex, false),
+                Event::DisplayMath(tex) => (tex, true),
+                _ => return Ok(event),
+            };
+            let mathml = match formulas.get(&(tex.to_string(), display)) {
+                Some(mathml) => mathml.clone()?,
+                None => bail!("formula {tex:?} wasn't converted while parsing"),
+            };
+            Ok(match mathml {
+                Some(mathml) => Event::InlineHtml(mathml.into()),
+                // Rendered as `<span class="math math-inline">` with the TeX.
+                None => event,
+            })
+        })
+        .collect()
+}
+
+/// Replaces fenced code blocks in known languages or with attributes by highlighted HTML, see
+/// `highlight::code_block`. Blocks like ```` ```include:src/main.rs#L10-L20 ```` are replaced by
+/// the contents of that file, see `include`. `lines` has the line of every fenced block.
+/// Attributes that aren't known are added to `warnings` and ignored.
+fn code_blocks<'a>(
+    events: Vec<Event<'a>>,
+    lines: &[usize],
+    dir: &Path,
+    warnings: &mut Vec<Error>,
+) -> Result<(Vec<Event<'a>>, Vec<PathBuf>)> {
+    let mut new_events = Vec::with_capacity(events.len());
+    let mut includes = Vec::new();
+    let mut lines = lines.iter();
+    // Info string, line and events of the current code block.
+    let mut block: Option<(CowStr, usize, Vec<Event>)> = None;
+
+    for event in events {
+        match event {
+            Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(ref info))) => {
+                block = Some((info.clone(), *lines.next().unwrap(), vec![event]));
+            }
+            Event::End(TagEnd::CodeBlock) if block.is_some() => {
+                let (info, line, mut inner) = block.take().unwrap();
+                let error = |e: anyhow::Error| Error {
+                    line,
+                    message: format!("{e:#}"),
+                };
+                let mut code: String = inner
+                    .iter()
+                    .filter_map(|e| match e {
+                        Event::Text(t) => Some(t.as_ref()),
+                        _ => None,
+                    })
+                    .collect();
+                let mut info = crate::highlight::Info::parse(&info).map_err(error)?;
+                for attribute in std::mem::take(&mut info.unknown) {
+                    warnings.push(Error {
+                        line,
+                        message: format!("ignoring unknown code block attribute {attribute:?}, expected `hl=<lines>`, `linenos` or `title=\"...\"`"),
+                    });
+                }
+                if let Some(spec) = info.lang.strip_prefix("include:") {
+                    let (path, included) = include(dir, spec).map_err(error)?;
+                    info.lang = path
+                        .extension()
+                        .map(|e| e.to_string_lossy().into_owned())
+                        .unwrap_or_default();
+                    code = included;
+                    includes.push(path);
+                }
+                match crate::highlight::code_block(&code, &info).map_err(error)? {
+                    Some(html) => new_events.push(Event::Html(html.into())),
+                    None => {
+                        inner.push(event);
+                        new_events.extend(inner);
+                    }
+                }
+            }
+            _ => match block.as_mut() {
+                Some((_, _, inner)) => inner.push(event),
+                None => new_events.push(event),
+            },
+        }
+    }
+    Ok((new_events, includes))
 }
 
-/// Generate footnotes as bottom-notes, in the style of GitHub.
+/// Reads the part of a file given by `spec`, relative to `dir`: either all of `path`, lines
+/// `path#L10-L20` (or just `path#L10`), or the region `path#name`. Regions are marked with
+/// `ANCHOR: name` and `ANCHOR_END: name` lines, which are never included themselves.
+fn include(dir: &Path, spec: &str) -> Result<(PathBuf, String)> {
+    let (path, part) = match spec.split_once('#') {
+        Some((path, part)) => (path, Some(part)),
+        None => (spec, None),
+    };
+    let path = dir.join(path);
+    let contents =
+        std::fs::read_to_string(&path).map_err(|e| anyhow!("failed to include {path:?}: {e:}"))?;
+    let lines: Vec<&str> = contents.lines().collect();
+
+    let lines: Vec<&str> = match part {
+        None => lines,
+        Some(part) if is_line_range(part) => {
+            let (start, end) = part.split_once('-').unwrap_or((part, part));
+            let parse = |l: &str| l.trim_start_matches('L').parse::<usize>();
+            let (start, end) = (parse(start)?, parse(end)?);
+            if start == 0 || start > end || end > lines.len() {
+                bail!(
+                    "lines {part:} are out of range, {path:?} has {:} lines",
+                    lines.len()
+                );
+            }
+            lines[start - 1..end].to_vec()
+        }
+        Some(name) => {
+            let marker = |l: &&str, kind: &str| {
+                l.split_once(kind)
+                    .is_some_and(|(_, rest)| rest.split_whitespace().next() == Some(name))
+            };
+            let Some(start) = lines.iter().position(|l| marker(l, "ANCHOR:")) else {
+                bail!("there is no `ANCHOR: {name:}` in {path:?}");
+            };
+            let Some(end) = lines[start..].iter().position(|l| marker(l, "ANCHOR_END:")) else {
+                bail!("`ANCHOR: {name:}` in {path:?} is never closed by `ANCHOR_END: {name:}`");
+            };
+            lines[start + 1..start + end].to_vec()
+        }
+    };
+
+    let mut code = String::new();
+    for l in lines
+        .iter()
+        .filter(|l| !l.contains("ANCHOR:") && !l.contains("ANCHOR_END:"))
+    {
+        code.push_str(l);
+        code.push('\n');
+    }
+    Ok((path, code))
+}
+
+/// Whether `part` looks like `L10-L20` or `L10`.
+fn is_line_range(part: &str) -> bool {
+    part.split('-').all(|p| {
+        p.strip_prefix('L')
+            .is_some_and(|n| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()))
+    }) && part.split('-').count() <= 2
+}
+
+/// Turns a `{#id}` at the end of the heading that is about to end into its id, like in
+/// `## Setup {#setup}`. Anything else in braces is left as part of the title.
+fn explicit_heading_id(events: &mut Vec<Event>) {
+    let Some(Event::Text(text)) = events.last() else {
+        return;
+    };
+    let text = text.trim_end();
+    let Some(start) = text.rfind("{#") else {
+        return;
+    };
+    let Some((id, "")) = split_label(&text[start..]) else {
+        return;
+    };
+    let (id, title) = (id.to_owned(), text[..start].trim_end().to_owned());
+
+    let Some(Event::Start(Tag::Heading { id: heading_id, .. })) = events
+        .iter_mut()
+        .rfind(|e| matches!(e, Event::Start(Tag::Heading { .. })))
+    else {
+        return;
+    };
+    *heading_id = Some(id.into());
+    events.pop();
+    if !title.is_empty() {
+        events.push(Event::Text(title.into()));
+    }
+}
+
+/// Gives every heading an id, unless it has one like `## Title {#id}` already, and adds a
+/// permalink to it. Ids are slugs of the title, numbered if the same title appears more than
+/// once, and prefixed with `section-` if they would look like a footnote or reference id.
+/// `lines` has the line of every heading.
+fn heading_ids<'a>(events: Vec<Event<'a>>, lines: &[usize]) -> Result<Vec<Event<'a>>> {
+    // Explicit ids first, so that generated ones never collide with them.
+    let mut taken = HashSet::new();
+    let mut lines_iter = lines.iter();
+    for e in events.iter() {
+        if let Event::Start(Tag::Heading { id, .. }) = e {
+            let line = *lines_iter.next().unwrap();
+            if let Some(id) = id {
+                if !taken.insert(id.to_string()) {
+                    return Err(Error {
+                        line,
+                        message: format!("heading id {:?} is used more than once", id.as_ref()),
+                    }
+                    .into());
+                }
+            }
+        }
+    }
+
+    let titles: Vec<String> = headings(&events).into_iter().map(|h| h.title).collect();
+    let mut titles = titles.into_iter();
+    let mut current = None;
+    let mut new_events = Vec::with_capacity(events.len());
+    for e in events {
+        match e {
+            Event::Start(Tag::Heading {
+                level,
+                id,
+                classes,
+                attrs,
+            }) => {
+                let title = titles.next().unwrap();
+                let id = match id {
+                    Some(id) => id.to_string(),
+                    None => {
+                        let mut slug = slug(&title);
+                        if RESERVED_IDS.iter().any(|p| slug.starts_with(p)) {
+                            slug = format!("section-{slug:}");
+                        }
+                        let mut id = slug.clone();
+                        let mut n = 0;
+                        while !taken.insert(id.clone()) {
+                            n += 1;
+                            id = format!("{slug:}-{n:}");
+                        }
+                        id
+                    }
+                };
+                current = Some(id.clone());
+                new_events.push(Event::Start(Tag::Heading {
+                    level,
+                    id: Some(id.into()),
+                    classes,
+                    attrs,
+                }));
+            }
+            Event::End(TagEnd::Heading(_)) => {
+                if let Some(id) = current.take() {
+                    new_events.push(Event::InlineHtml(
+                        format!(
+                            r##" <a class="permalink" href="#{id}" aria-label="Permalink">#</a>"##
+                        )
+                        .into(),
+                    ));
+                }
+                new_events.push(e);
+            }
+            _ => new_events.push(e),
+        }
+    }
+    Ok(new_events)
+}
+
+/// Prefixes of the ids given to footnotes, footnote references and bibliography entries.
+const RESERVED_IDS: [&str; 3] = ["fn-", "fr-", "ref-"];
+
+/// Lowercase `title` with words separated by `-`, and everything but letters and digits dropped.
+fn slug(title: &str) -> String {
+    let mut slug = String::new();
+    for c in title.chars() {
+        if c.is_alphanumeric() {
+            slug.extend(c.to_lowercase());
+        } else if (c.is_whitespace() || c == '-' || c == '_') && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    let slug = slug.trim_matches('-');
+    if slug.is_empty() {
+        "section".to_owned()
+    } else {
+        slug.to_owned()
+    }
+}
+
+/// Nests `headings` below the closest preceding heading of a higher level.
+fn toc(headings: &[Heading]) -> Vec<TocEntry> {
+    fn insert(entries: &mut Vec<TocEntry>, entry: TocEntry) {
+        match entries.last_mut() {
+            Some(last) if last.level < entry.level => insert(&mut last.children, entry),
+            _ => entries.push(entry),
+        }
+    }
+
+    let mut entries = Vec::new();
+    for h in headings {
+        insert(
+            &mut entries,
+            TocEntry {
+                level: h.level,
+                id: h.id.clone().unwrap_or_default(),
+                title: h.title.clone(),
+                children: vec![],
+            },
+        );
+    }
+    entries
+}
+
+fn toc_html(entries: &[TocEntry], html: &mut String) {
+    html.push_str("<ul>\n");
+    for e in entries {
+        write!(
+            html,
+            r##"<li><a href="#{}">{}</a>"##,
+            e.id,
+            crate::highlight::escape_html(&e.title)
+        )
+        .unwrap();
+        if !e.children.is_empty() {
+            html.push('\n');
+            toc_html(&e.children, html);
+        }
+        html.push_str("</li>\n");
+    }
+    html.push_str("</ul>\n");
+}
+
+/// Replaces paragraphs consisting of just `[TOC]` by a table of contents.
+fn toc_marker<'a>(events: Vec<Event<'a>>, headings: &[Heading]) -> Vec<Event<'a>> {
+    let mut new_events = Vec::with_capacity(events.len());
+    for e in events {
+        new_events.push(e);
+        if let [.., Event::Start(Tag::Paragraph), Event::Text(t), Event::End(TagEnd::Paragraph)] =
+            new_events.as_slice()
+        {
+            if t.trim() == "[TOC]" {
+                new_events.truncate(new_events.len() - 3);
+                let mut html = String::from("<nav class=\"toc\">\n");
+                toc_html(&toc(headings), &mut html);
+                html.push_str("</nav>\n");
+                new_events.push(Event::Html(html.into()));
+            }
+        }
+    }
+    new_events
+}
+
+/// Collects the plain text of all headings, in document order.
+fn headings(events: &[Event]) -> Vec<Heading> {
+    let mut headings = Vec::new();
+    let mut current: Option<Heading> = None;
+    for e in events {
+        match e {
+            Event::Start(Tag::Heading { level, id, .. }) => {
+                current = Some(Heading {
+                    level: *level as usize,
+                    id: id.as_ref().map(|id| id.to_string()),
+                    title: String::new(),
+                })
+            }
+            Event::End(TagEnd::Heading(_)) => headings.extend(current.take()),
+            Event::Text(t) | Event::Code(t) | Event::InlineMath(t) => {
+                if let Some(h) = current.as_mut() {
+                    h.title.push_str(t);
+                }
+            }
+            _ => {}
+        }
+    }
+    headings
+}
+
+/// Generate footnotes as bottom-notes, in the style of GitHub, or as sidenotes next to their
+/// first reference.
 /// From https://github.com/pulldown-cmark/pulldown-cmark/blob/master/pulldown-cmark/examples/footnote-rewrite.rs
-fn bottom_footnotes(events: Vec<Event>) -> Vec<Event> {
+fn footnotes(events: Vec<Event<'_>>, style: FootnoteStyle) -> Vec<Event<'_>> {
     // To generate this style, you have to collect the footnotes at the end, while parsing.
     // You also need to count usages.
     let mut footnotes = Vec::new();
     let mut in_footnote = Vec::new();
     let mut footnote_numbers = std::collections::HashMap::new();
+    // Footnotes to show as sidenotes, after the position of their first reference in
+    // `new_events`.
+    let mut sidenotes = Vec::new();
 
-    let mut new_events: Vec<_> = events.into_iter().filter_map(|event| {
-            match event {
-                Event::Start(Tag::FootnoteDefinition(_)) => {
-                    in_footnote.push(vec![event]);
-                    None
-                }
-                Event::End(TagEnd::FootnoteDefinition) => {
-                    let mut f = in_footnote.pop().unwrap();
-                    f.push(event);
-                    footnotes.push(f);
-                    None
-                }
-                Event::FootnoteReference(name) => {
-                    let n = footnote_numbers.len() + 1;
-                    let (n, nr) = footnote_numbers.entry(name.clone()).or_insert((n, 0usize));
-                    *nr += 1;
-                    let html = Event::Html(format!(r##"<sup class="footnote-reference" id="fr-{name}-{nr}"><a href="#fn-{name}">[{n}]</a></sup>"##).into());
-                    if in_footnote.is_empty() {
-                        Some(html)
-                    } else {
-                        in_footnote.last_mut().unwrap().push(html);
-                        None
-                    }
+    let mut new_events = Vec::with_capacity(events.len());
+    for event in events {
+        match event {
+            Event::Start(Tag::FootnoteDefinition(_)) => in_footnote.push(vec![event]),
+            Event::End(TagEnd::FootnoteDefinition) => {
+                let mut f = in_footnote.pop().unwrap();
+                f.push(event);
+                footnotes.push(f);
+            }
+            Event::FootnoteReference(name) => {
+                let n = footnote_numbers.len() + 1;
+                let (n, nr) = footnote_numbers.entry(name.clone()).or_insert((n, 0usize));
+                *nr += 1;
+                let html = Event::InlineHtml(format!(r##"<sup class="footnote-reference" id="fr-{name}-{nr}"><a href="#fn-{name}">[{n}]</a></sup>"##).into());
+                if let Some(f) = in_footnote.last_mut() {
+                    f.push(html);
+                    continue;
                 }
-                _ if !in_footnote.is_empty() => {
-                    in_footnote.last_mut().unwrap().push(event);
-                    None
+                if style == FootnoteStyle::Sidenotes && *nr == 1 {
+                    // The sidenote is added once all definitions are known.
+                    sidenotes.push((new_events.len(), name));
                 }
-                _ => Some(event),
+                new_events.push(html);
             }
-        }).collect();
+            _ if !in_footnote.is_empty() => in_footnote.last_mut().unwrap().push(event),
+            _ => new_events.push(event),
+        }
+    }
 
     // To make the footnotes look right, we need to sort them by their appearance order, not by
     // the in-tree order of their actual definitions. Unused items are omitted entirely.
@@ -92,6 +1304,10 @@ fn bottom_footnotes(events: Vec<Event>) -> Vec<Event> {
     //     <li>test ↩</li>
     //     <li>second used, first defined ↩</li>
     //     </ol>
+    if !sidenotes.is_empty() {
+        new_events = sidenote_markers(new_events, sidenotes, &mut footnotes, &footnote_numbers);
+    }
+
     if !footnotes.is_empty() {
         footnotes.retain(|f| match f.first() {
             Some(Event::Start(Tag::FootnoteDefinition(name))) => {
@@ -177,7 +1393,251 @@ fn bottom_footnotes(events: Vec<Event>) -> Vec<Event> {
                 f => f,
             })
         }));
+        new_events.push(Event::Html("</ol>\n".into()));
     }
 
     new_events
 }
+
+/// Adds each footnote in `sidenotes` after the event at its position, as a margin note.
+/// Footnotes shown this way are removed from `footnotes`, the rest still go to the bottom.
+fn sidenote_markers<'a>(
+    events: Vec<Event<'a>>,
+    sidenotes: Vec<(usize, CowStr<'a>)>,
+    footnotes: &mut Vec<Vec<Event<'a>>>,
+    footnote_numbers: &HashMap<CowStr<'a>, (usize, usize)>,
+) -> Vec<Event<'a>> {
+    let mut sidenotes = sidenotes.into_iter().peekable();
+    let mut new_events = Vec::with_capacity(events.len());
+    for (i, event) in events.into_iter().enumerate() {
+        new_events.push(event);
+        let Some((_, name)) = sidenotes.next_if(|(at, _)| *at == i) else {
+            continue;
+        };
+        let Some(i) = footnotes.iter().position(
+            |f| matches!(f.first(), Some(Event::Start(Tag::FootnoteDefinition(n))) if *n == name),
+        ) else {
+            // Never defined, the reference leads nowhere either way.
+            continue;
+        };
+        if !fits_sidenote(&footnotes[i]) {
+            // Stays at the bottom, the reference leads there.
+            continue;
+        }
+        let footnote = footnotes.remove(i);
+        let n = footnote_numbers[&name].0;
+
+        // Sidenotes are inline, so paragraphs are only separated by line breaks.
+        new_events.push(Event::InlineHtml(
+            format!(
+                r#"<span class="sidenote" id="fn-{name}"><span class="sidenote-number">{n}</span> "#
+            )
+            .into(),
+        ));
+        let mut paragraphs = 0;
+        for e in footnote {
+            match e {
+                Event::Start(Tag::FootnoteDefinition(_))
+                | Event::End(TagEnd::FootnoteDefinition) => {}
+                Event::Start(Tag::Paragraph) => {
+                    if paragraphs > 0 {
+                        new_events.push(Event::InlineHtml("<br />".into()));
+                    }
+                    paragraphs += 1;
+                }
+                Event::End(TagEnd::Paragraph) => {}
+                e => new_events.push(e),
+            }
+        }
+        new_events.push(Event::InlineHtml("</span>".into()));
+    }
+    new_events
+}
+
+/// Whether `footnote` only has paragraphs of inline content, which can be shown inline as a
+/// sidenote. Lists, code blocks, quotes and the like can't be nested into a paragraph.
+fn fits_sidenote(footnote: &[Event]) -> bool {
+    footnote.iter().all(|e| match e {
+        Event::Start(t) => matches!(
+            t,
+            Tag::FootnoteDefinition(_)
+                | Tag::Paragraph
+                | Tag::Emphasis
+                | Tag::Strong
+                | Tag::Strikethrough
+                | Tag::Link { .. }
+                | Tag::Image { .. }
+        ),
+        Event::Html(_) | Event::Rule => false,
+        _ => true,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options() -> Options<'static> {
+        Options {
+            dir: Path::new(""),
+            bibliography: None,
+            archived: &[],
+            footnotes: FootnoteStyle::Bottom,
+            summary_words: 100,
+        }
+    }
+
+    fn html(markdown: &str) -> String {
+        analyze(markdown, &options()).unwrap().html
+    }
+
+    fn warnings(markdown: &str) -> Vec<String> {
+        let analysis = analyze(markdown, &options()).unwrap();
+        analysis.warnings.iter().map(|w| w.to_string()).collect()
+    }
+
+    fn error(markdown: &str) -> String {
+        match analyze(markdown, &options()) {
+            Ok(a) => panic!("expected an error, got {:}", a.html),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn math_in_figure_caption() {
+        let html = html("![plot of $y$](/img.png){#fig:a}\n\nSee @fig:a.");
+        assert!(html.contains(r#"alt="plot of y""#), "{html}");
+        assert_eq!(html.matches("<math").count(), 1, "{html}");
+        assert!(
+            html.contains(r#"<span class="caption">Figure 1: plot of <math"#),
+            "{html}"
+        );
+        assert!(
+            html.contains(r##"<a href="#fig:a">Figure 1</a>"##),
+            "{html}"
+        );
+    }
+
+    #[test]
+    fn math_errors_have_lines() {
+        assert_eq!(
+            error("Fine $x$.\n\n![bad $\\frac{a}$](/img.png){#fig:a}"),
+            r"line 3: invalid math: missing argument"
+        );
+        assert_eq!(
+            error("Fine $x$.\n\n$\\foo \\left( x$"),
+            r"line 3: invalid math: expected \right, but the math ended"
+        );
+    }
+
+    #[test]
+    fn unsupported_math_is_left_to_the_browser() {
+        let markdown = "Fine $x$.\n\n![plot of $\\foo$](/img.png){#fig:a}\n\n$$\\foo < 1$$";
+        let html = html(markdown);
+        assert!(
+            html.contains(r#"alt="plot of \foo""#)
+                && html.contains(
+                    r#"<span class="caption">Figure 1: plot of <span class="math math-inline">\foo</span>"#
+                )
+                && html.contains(r#"<span class="math math-display">\foo &lt; 1</span>"#),
+            "{html}"
+        );
+        assert_eq!(html.matches("<math").count(), 1, "{html}");
+        assert_eq!(
+            warnings(markdown),
+            [
+                r"line 3: unsupported TeX command \foo, leaving the formula to be rendered in the browser",
+                r"line 5: unsupported TeX command \foo, leaving the formula to be rendered in the browser",
+            ]
+        );
+    }
+
+    #[test]
+    fn citations_need_a_bibliography() {
+        assert_eq!(
+            html("Thanks to my reviewer [@alice]."),
+            "<p>Thanks to my reviewer [@alice].</p>\n"
+        );
+    }
+
+    #[test]
+    fn sidenotes_only_for_inline_footnotes() {
+        let options = Options {
+            footnotes: FootnoteStyle::Sidenotes,
+            ..options()
+        };
+        let markdown = "Short[^a] and long[^b].\n\n[^a]: One *line*.\n\n    Two.\n\n[^b]: A list:\n\n    - one\n    - two\n";
+        let html = analyze(markdown, &options).unwrap().html;
+        assert!(
+            html.starts_with(
+                r##"<p>Short<sup class="footnote-reference" id="fr-a-1"><a href="#fn-a">[1]</a></sup><span class="sidenote" id="fn-a"><span class="sidenote-number">1</span> One <em>line</em>.<br />Two.</span> and long<sup"##
+            ),
+            "{html}"
+        );
+        let (text, bottom) = html.split_once(r#"<ol class="footnotes-list">"#).unwrap();
+        assert!(!text.contains("<ul>"), "{html}");
+        assert!(bottom.contains(r#"<li id="fn-b">"#), "{html}");
+        assert!(bottom.contains("<li>two</li>"), "{html}");
+        assert!(!bottom.contains(r#"id="fn-a""#), "{html}");
+    }
+
+    #[test]
+    fn heading_ids() {
+        assert_eq!(
+            html("## Setup {#setup}\n\n## The set {a, b}\n\n## `code` {.class}\n\n## Setup\n\n## Fn 1"),
+            concat!(
+                r##"<h2 id="setup">Setup <a class="permalink" href="#setup" aria-label="Permalink">#</a></h2>"##,
+                "\n",
+                r##"<h2 id="the-set-a-b">The set {a, b} <a class="permalink" href="#the-set-a-b" aria-label="Permalink">#</a></h2>"##,
+                "\n",
+                r##"<h2 id="code-class"><code>code</code> {.class} <a class="permalink" href="#code-class" aria-label="Permalink">#</a></h2>"##,
+                "\n",
+                r##"<h2 id="setup-1">Setup <a class="permalink" href="#setup-1" aria-label="Permalink">#</a></h2>"##,
+                "\n",
+                r##"<h2 id="section-fn-1">Fn 1 <a class="permalink" href="#section-fn-1" aria-label="Permalink">#</a></h2>"##,
+                "\n",
+            )
+        );
+    }
+
+    #[test]
+    fn summaries_link_to_the_post() {
+        let options = Options {
+            summary_words: 20,
+            ..options()
+        };
+        let analysis = analyze(
+            "## Loss\n\n$$ L $$ {#eq:loss}\n\nSee @eq:loss and [below](#loss).",
+            &options,
+        )
+        .unwrap();
+        let summary = detach_summary(&analysis.summary, "https://example.com/blog/post/").unwrap();
+        assert!(!summary.contains("id="), "{summary}");
+        assert!(
+            summary.contains(r#"<a href="https://example.com/blog/post/#eq:loss">Eq. (1)</a>"#),
+            "{summary}"
+        );
+        assert!(
+            summary.contains(r#"<a href="https://example.com/blog/post/#loss">below</a>"#),
+            "{summary}"
+        );
+    }
+
+    #[test]
+    fn unknown_code_block_attributes_are_ignored() {
+        let markdown = "Code:\n\n```python {.numberLines startFrom=3 linenos}\nx = 1\n```\n";
+        assert!(
+            html(markdown).contains(r#"<span class="line-number">1</span>"#),
+            "{}",
+            html(markdown)
+        );
+        assert_eq!(
+            warnings(markdown),
+            [
+                r#"line 3: ignoring unknown code block attribute ".numberLines", expected `hl=<lines>`, `linenos` or `title="..."`"#,
+                r#"line 3: ignoring unknown code block attribute "startFrom=3", expected `hl=<lines>`, `linenos` or `title="..."`"#,
+            ]
+        );
+        assert!(error("```python {hl=x}\nx = 1\n```").contains("invalid line range"));
+    }
+}
@@ -0,0 +1,1498 @@
+use anyhow::{bail, Result};
+
+/// Valid TeX outside of the supported subset, as opposed to invalid TeX like unbalanced braces.
+/// Returned by `to_mathml` (within an `anyhow::Error`), so that callers can fall back to
+/// rendering such formulas in the browser.
+#[derive(Debug)]
+pub struct Unsupported(pub String);
+
+impl std::fmt::Display for Unsupported {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Unsupported {}
+
+/// Converts TeX math to MathML, so that pages don't need a script to render it. Only the commonly
+/// used subset of TeX is supported, anything else is an `Unsupported` error.
+pub fn to_mathml(tex: &str, display: bool) -> Result<String> {
+    let tokens = tokenize(tex)?;
+    let mut parser = Parser {
+        tokens: tokens.clone(),
+        pos: 0,
+        variant: None,
+    };
+    let body = match parser.table_or_row() {
+        // Parsing stops there, but the rest may still be invalid.
+        Err(e) if e.is::<Unsupported>() => {
+            check_balanced(&tokens)?;
+            return Err(e);
+        }
+        body => body?,
+    };
+    if let Some(t) = parser.peek() {
+        bail!("unexpected {}", t.describe());
+    }
+
+    Ok(format!(
+        r#"<math xmlns="http://www.w3.org/1998/Math/MathML"{}><semantics>{}<annotation encoding="application/x-tex">{}</annotation></semantics></math>"#,
+        if display { r#" display="block""# } else { "" },
+        mrow(vec![body]),
+        escape(tex.trim()),
+    ))
+}
+
+/// Finds unbalanced braces, `\left` and `\right` and environments, which are errors whatever
+/// the commands in between are.
+fn check_balanced(tokens: &[Token]) -> Result<()> {
+    let tokens: Vec<&Token> = tokens.iter().filter(|t| **t != Token::Space).collect();
+    // The name of the environment starting at `tokens[i]`, like `{aligned}`.
+    let name = |i: usize| -> String {
+        tokens[i..]
+            .iter()
+            .take_while(|t| **t != &Token::Close)
+            .map(|t| t.describe())
+            .chain(["}".to_owned()])
+            .collect()
+    };
+    // What closes each group that is currently open.
+    let mut open: Vec<String> = Vec::new();
+    for (i, t) in tokens.iter().enumerate() {
+        let closes = match t {
+            Token::Open => {
+                open.push("}".to_owned());
+                continue;
+            }
+            Token::Command(c) if c == "left" => {
+                open.push("\\right".to_owned());
+                continue;
+            }
+            Token::Command(c) if c == "begin" => {
+                open.push(format!("\\end{:}", name(i + 1)));
+                continue;
+            }
+            Token::Close => "}".to_owned(),
+            Token::Command(c) if c == "right" => "\\right".to_owned(),
+            Token::Command(c) if c == "end" => format!("\\end{:}", name(i + 1)),
+            _ => continue,
+        };
+        match open.pop() {
+            Some(expected) if expected == closes => {}
+            Some(expected) => bail!("expected {expected}, got {closes}"),
+            None => bail!("unexpected {closes}"),
+        }
+    }
+    match open.pop() {
+        Some(expected) => bail!("expected {expected}, but the math ended"),
+        None => Ok(()),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    // `\name`, or `\` followed by a single other character.
+    Command(String),
+    Char(char),
+    Space,
+    Open,
+    Close,
+    Sup,
+    Sub,
+    Align,
+}
+
+impl Token {
+    fn describe(&self) -> String {
+        match self {
+            Token::Command(c) => format!("\\{c}"),
+            Token::Char(c) => c.to_string(),
+            Token::Space => " ".to_owned(),
+            Token::Open => "{".to_owned(),
+            Token::Close => "}".to_owned(),
+            Token::Sup => "^".to_owned(),
+            Token::Sub => "_".to_owned(),
+            Token::Align => "&".to_owned(),
+        }
+    }
+}
+
+fn tokenize(tex: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = tex.chars().peekable();
+    while let Some(c) = chars.next() {
+        tokens.push(match c {
+            '\\' => match chars.next() {
+                Some(c) if c.is_ascii_alphabetic() => {
+                    let mut name = c.to_string();
+                    while let Some(c) = chars.next_if(|c| c.is_ascii_alphabetic()) {
+                        name.push(c);
+                    }
+                    Token::Command(name)
+                }
+                Some(c) => Token::Command(c.to_string()),
+                None => bail!("math ends with a lone \\"),
+            },
+            '%' => {
+                while chars.next_if(|c| *c != '\n').is_some() {}
+                continue;
+            }
+            '{' => Token::Open,
+            '}' => Token::Close,
+            '^' => Token::Sup,
+            '_' => Token::Sub,
+            '&' => Token::Align,
+            c if c.is_whitespace() => {
+                while chars.next_if(|c| c.is_whitespace()).is_some() {}
+                Token::Space
+            }
+            c => Token::Char(c),
+        });
+    }
+    Ok(tokens)
+}
+
+/// Alphabets selected by `\mathbf` and friends.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Variant {
+    Normal,
+    Bold,
+    BoldItalic,
+    Script,
+    Fraktur,
+    DoubleStruck,
+    SansSerif,
+    Monospace,
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    variant: Option<Variant>,
+}
+
+/// A parsed piece of math, and whether scripts go below and above it instead of to its side.
+struct Atom {
+    mathml: String,
+    limits: bool,
+}
+
+impl Atom {
+    fn new(mathml: String) -> Atom {
+        Atom {
+            mathml,
+            limits: false,
+        }
+    }
+}
+
+impl Parser {
+    fn peek(&mut self) -> Option<&Token> {
+        while self.tokens.get(self.pos) == Some(&Token::Space) {
+            self.pos += 1;
+        }
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        self.peek()?;
+        self.pos += 1;
+        Some(self.tokens[self.pos - 1].clone())
+    }
+
+    fn expect(&mut self, token: Token) -> Result<()> {
+        match self.next() {
+            Some(t) if t == token => Ok(()),
+            Some(t) => bail!("expected {}, got {}", token.describe(), t.describe()),
+            None => bail!("expected {}, but the math ended", token.describe()),
+        }
+    }
+
+    fn peek_command(&mut self, name: &str) -> bool {
+        matches!(self.peek(), Some(Token::Command(c)) if c == name)
+    }
+
+    /// Rows separated by `\\` with cells separated by `&`, as a table if there is more than one
+    /// cell.
+    fn table_or_row(&mut self) -> Result<String> {
+        let mut rows = self.table_rows()?;
+        if rows.len() == 1 && rows[0].len() == 1 {
+            return Ok(rows.pop().unwrap().pop().unwrap());
+        }
+        Ok(mtable(rows, &["center"], false))
+    }
+
+    fn table_rows(&mut self) -> Result<Vec<Vec<String>>> {
+        let mut rows = Vec::new();
+        loop {
+            let mut cells = vec![self.row()?];
+            while self.peek() == Some(&Token::Align) {
+                self.next();
+                cells.push(self.row()?);
+            }
+            rows.push(cells);
+
+            if !self.peek_command("\\") {
+                break;
+            }
+            self.next();
+            // Extra vertical space, like `\\[2pt]`.
+            if self.peek() == Some(&Token::Char('[')) {
+                self.bracketed_tokens()?;
+            }
+        }
+        // A trailing `\\` doesn't start another row.
+        if rows.len() > 1
+            && rows
+                .last()
+                .is_some_and(|r| r.len() == 1 && r[0] == "<mrow></mrow>")
+        {
+            rows.pop();
+        }
+        Ok(rows)
+    }
+
+    /// Parses everything up to the end of the current group, cell or row.
+    fn row(&mut self) -> Result<String> {
+        let mut items = Vec::new();
+        loop {
+            match self.peek() {
+                None | Some(Token::Close) | Some(Token::Align) => break,
+                Some(Token::Command(c)) if ["\\", "right", "end"].contains(&c.as_str()) => break,
+                Some(Token::Command(c)) if c.ends_with("style") => {
+                    let display = match c.as_str() {
+                        "displaystyle" => "true",
+                        "textstyle" | "scriptstyle" | "scriptscriptstyle" => "false",
+                        _ => break,
+                    };
+                    self.next();
+                    let rest = self.row()?;
+                    items.push(format!(
+                        r#"<mstyle displaystyle="{display}">{rest}</mstyle>"#
+                    ));
+                    break;
+                }
+                _ => {}
+            }
+            items.push(self.item()?);
+        }
+        Ok(mrow(items))
+    }
+
+    /// An atom with its sub- and superscripts.
+    fn item(&mut self) -> Result<String> {
+        let mut base = self.atom(false)?;
+        loop {
+            if self.peek_command("limits") {
+                base.limits = true;
+            } else if self.peek_command("nolimits") {
+                base.limits = false;
+            } else {
+                break;
+            }
+            self.next();
+        }
+        let (mut sub, mut sup) = (None, None);
+        loop {
+            match self.peek() {
+                Some(Token::Sub) => {
+                    self.next();
+                    if sub.is_some() {
+                        bail!("double subscript");
+                    }
+                    sub = Some(self.script()?);
+                }
+                Some(Token::Sup) => {
+                    self.next();
+                    if sup.is_some() {
+                        bail!("double superscript");
+                    }
+                    sup = Some(self.script()?);
+                }
+                Some(Token::Char('\'')) if sup.is_none() => {
+                    let mut primes = String::new();
+                    while self.peek() == Some(&Token::Char('\'')) {
+                        self.next();
+                        primes.push('′');
+                    }
+                    sup = Some(format!("<mo>{primes}</mo>"));
+                }
+                _ => break,
+            }
+        }
+
+        let b = base.mathml;
+        Ok(match (sub, sup, base.limits) {
+            (None, None, _) => b,
+            (Some(s), None, false) => format!("<msub>{b}{s}</msub>"),
+            (None, Some(s), false) => format!("<msup>{b}{s}</msup>"),
+            (Some(s), Some(t), false) => format!("<msubsup>{b}{s}{t}</msubsup>"),
+            (Some(s), None, true) => format!("<munder>{b}{s}</munder>"),
+            (None, Some(s), true) => format!("<mover>{b}{s}</mover>"),
+            (Some(s), Some(t), true) => format!("<munderover>{b}{s}{t}</munderover>"),
+        })
+    }
+
+    fn script(&mut self) -> Result<String> {
+        match self.peek() {
+            None | Some(Token::Close) | Some(Token::Align) | Some(Token::Sub)
+            | Some(Token::Sup) => {
+                bail!("missing script after ^ or _")
+            }
+            _ => Ok(self.atom(true)?.mathml),
+        }
+    }
+
+    /// A required argument, either a single token or a group.
+    fn argument(&mut self) -> Result<String> {
+        match self.peek() {
+            None | Some(Token::Close) | Some(Token::Align) => bail!("missing argument"),
+            _ => Ok(self.atom(true)?.mathml),
+        }
+    }
+
+    /// The plain text of a group, like the name in `\begin{name}`.
+    fn text_argument(&mut self) -> Result<String> {
+        self.expect(Token::Open)?;
+        let mut text = String::new();
+        let mut depth = 0;
+        loop {
+            let Some(t) = self.tokens.get(self.pos).cloned() else {
+                bail!("missing }}");
+            };
+            self.pos += 1;
+            match t {
+                Token::Close if depth == 0 => break,
+                Token::Close => depth -= 1,
+                Token::Open => depth += 1,
+                Token::Command(c) if [",", ":", ";", " ", "space"].contains(&c.as_str()) => {
+                    text.push(' ')
+                }
+                Token::Command(c) if c == "!" => {}
+                Token::Command(c) if c.len() == 1 => text.push_str(&c),
+                _ => text.push_str(&t.describe()),
+            }
+        }
+        Ok(text)
+    }
+
+    /// Tokens of an optional argument in square brackets, like the 3 in `\sqrt[3]{x}`.
+    fn bracketed_tokens(&mut self) -> Result<Vec<Token>> {
+        self.expect(Token::Char('['))?;
+        let start = self.pos;
+        let mut depth = 0;
+        loop {
+            match self.tokens.get(self.pos) {
+                None => bail!("missing ]"),
+                Some(Token::Open) => depth += 1,
+                Some(Token::Close) => depth -= 1,
+                Some(Token::Char(']')) if depth == 0 => break,
+                _ => {}
+            }
+            self.pos += 1;
+        }
+        self.pos += 1;
+        Ok(self.tokens[start..self.pos - 1].to_vec())
+    }
+
+    fn optional_argument(&mut self) -> Result<Option<String>> {
+        if self.peek() != Some(&Token::Char('[')) {
+            return Ok(None);
+        }
+        let mut parser = Parser {
+            tokens: self.bracketed_tokens()?,
+            pos: 0,
+            variant: self.variant,
+        };
+        Ok(Some(parser.row()?))
+    }
+
+    fn with_variant(&mut self, variant: Variant) -> Result<String> {
+        let previous = self.variant.replace(variant);
+        let argument = self.argument();
+        self.variant = previous;
+        argument
+    }
+
+    /// A single piece of math without scripts. Within scripts and arguments (`single`), numbers
+    /// are only taken one digit at a time, like in TeX.
+    fn atom(&mut self, single: bool) -> Result<Atom> {
+        let Some(token) = self.peek().cloned() else {
+            return Ok(Atom::new(mrow(vec![])));
+        };
+        match token {
+            // Scripts without anything to attach to.
+            Token::Sub | Token::Sup => return Ok(Atom::new(mrow(vec![]))),
+            Token::Close => bail!("unmatched }}"),
+            Token::Align => bail!("& is only allowed in environments like aligned"),
+            _ => {}
+        }
+        self.next();
+
+        Ok(Atom::new(match token {
+            Token::Open => {
+                let row = self.row()?;
+                self.expect(Token::Close)?;
+                row
+            }
+            Token::Char(c) if c.is_ascii_digit() || c == '.' => {
+                let mut number = c.to_string();
+                while let Some(Token::Char(c)) = self.tokens.get(self.pos) {
+                    if single || !(c.is_ascii_digit() || *c == '.') {
+                        break;
+                    }
+                    number.push(*c);
+                    self.pos += 1;
+                }
+                let number = number.chars().map(|c| self.styled(c)).collect::<String>();
+                format!("<mn>{}</mn>", escape(&number))
+            }
+            Token::Char(c) if c.is_alphabetic() => self.identifier(c),
+            Token::Char('-') => "<mo>−</mo>".to_owned(),
+            Token::Char('\'') => "<mo>′</mo>".to_owned(),
+            Token::Char('~') => "<mtext>&#xA0;</mtext>".to_owned(),
+            Token::Char(c) => format!("<mo>{}</mo>", escape(&c.to_string())),
+            Token::Command(name) => return self.command(&name),
+            _ => unreachable!(),
+        }))
+    }
+
+    fn identifier(&self, c: char) -> String {
+        match self.variant {
+            Some(Variant::Normal) => format!(r#"<mi mathvariant="normal">{c}</mi>"#),
+            // Styled letters are separate characters, which don't get italicized.
+            Some(_) => format!("<mi>{}</mi>", self.styled(c)),
+            None => format!("<mi>{}</mi>", escape(&c.to_string())),
+        }
+    }
+
+    /// `c` in the current alphabet, see the Mathematical Alphanumeric Symbols Unicode block.
+    fn styled(&self, c: char) -> char {
+        let Some(variant) = self.variant else {
+            return c;
+        };
+        let exception = match (variant, c) {
+            (Variant::Script, 'B') => Some('ℬ'),
+            (Variant::Script, 'E') => Some('ℰ'),
+            (Variant::Script, 'F') => Some('ℱ'),
+            (Variant::Script, 'H') => Some('ℋ'),
+            (Variant::Script, 'I') => Some('ℐ'),
+            (Variant::Script, 'L') => Some('ℒ'),
+            (Variant::Script, 'M') => Some('ℳ'),
+            (Variant::Script, 'R') => Some('ℛ'),
+            (Variant::Script, 'e') => Some('ℯ'),
+            (Variant::Script, 'g') => Some('ℊ'),
+            (Variant::Script, 'o') => Some('ℴ'),
+            (Variant::Fraktur, 'C') => Some('ℭ'),
+            (Variant::Fraktur, 'H') => Some('ℌ'),
+            (Variant::Fraktur, 'I') => Some('ℑ'),
+            (Variant::Fraktur, 'R') => Some('ℜ'),
+            (Variant::Fraktur, 'Z') => Some('ℨ'),
+            (Variant::DoubleStruck, 'C') => Some('ℂ'),
+            (Variant::DoubleStruck, 'H') => Some('ℍ'),
+            (Variant::DoubleStruck, 'N') => Some('ℕ'),
+            (Variant::DoubleStruck, 'P') => Some('ℙ'),
+            (Variant::DoubleStruck, 'Q') => Some('ℚ'),
+            (Variant::DoubleStruck, 'R') => Some('ℝ'),
+            (Variant::DoubleStruck, 'Z') => Some('ℤ'),
+            _ => None,
+        };
+        if let Some(e) = exception {
+            return e;
+        }
+
+        // Start of the capital letters, small letters and digits of each alphabet.
+        let (upper, lower, digits) = match variant {
+            Variant::Normal => return c,
+            Variant::Bold => (0x1D400, 0x1D41A, Some(0x1D7CE)),
+            Variant::BoldItalic => (0x1D468, 0x1D482, Some(0x1D7CE)),
+            Variant::Script => (0x1D49C, 0x1D4B6, None),
+            Variant::Fraktur => (0x1D504, 0x1D51E, None),
+            Variant::DoubleStruck => (0x1D538, 0x1D552, Some(0x1D7D8)),
+            Variant::SansSerif => (0x1D5A0, 0x1D5BA, Some(0x1D7E2)),
+            Variant::Monospace => (0x1D670, 0x1D68A, Some(0x1D7F6)),
+        };
+        // Only bold alphabets have Greek letters, capitals followed by ∇, small letters and then
+        // the symbols in `GREEK_SYMBOLS`.
+        let greek: Option<u32> = match variant {
+            Variant::Bold => Some(0x1D6A8),
+            Variant::BoldItalic => Some(0x1D71C),
+            _ => None,
+        };
+        let code = match c {
+            'A'..='Z' => Some(upper + (c as u32 - 'A' as u32)),
+            'a'..='z' => Some(lower + (c as u32 - 'a' as u32)),
+            '0'..='9' => digits.map(|d| d + (c as u32 - '0' as u32)),
+            '\u{391}'..='\u{3A9}' => greek.map(|g| g + (c as u32 - 0x391)),
+            '\u{3B1}'..='\u{3C9}' => greek.map(|g| g + 26 + (c as u32 - 0x3B1)),
+            _ => greek
+                .zip(GREEK_SYMBOLS.chars().position(|s| s == c))
+                .map(|(g, i)| g + 51 + i as u32),
+        };
+        code.and_then(char::from_u32).unwrap_or(c)
+    }
+
+    fn command(&mut self, name: &str) -> Result<Atom> {
+        if let Some(&(_, c)) = GREEK.iter().find(|(n, _)| *n == name) {
+            let c = c.chars().next().unwrap();
+            return Ok(Atom::new(match self.variant {
+                Some(Variant::Bold | Variant::BoldItalic) => {
+                    format!("<mi>{}</mi>", self.styled(c))
+                }
+                _ if c.is_uppercase() => format!(r#"<mi mathvariant="normal">{c}</mi>"#),
+                _ => format!("<mi>{c}</mi>"),
+            }));
+        }
+        if let Some(&(_, c)) = IDENTIFIERS.iter().find(|(n, _)| *n == name) {
+            return Ok(Atom::new(format!("<mi>{c}</mi>")));
+        }
+        if let Some(&(_, c)) = OPERATORS.iter().find(|(n, _)| *n == name) {
+            return Ok(Atom::new(format!("<mo>{}</mo>", escape(c))));
+        }
+        if let Some(&(_, c, limits)) = LARGE_OPERATORS.iter().find(|(n, _, _)| *n == name) {
+            return Ok(Atom {
+                mathml: format!(r#"<mo largeop="true">{c}</mo>"#),
+                limits,
+            });
+        }
+        if FUNCTIONS.contains(&name) {
+            return Ok(Atom::new(format!("<mi>{name}</mi>")));
+        }
+        if let Some(&(_, f)) = LIMIT_FUNCTIONS.iter().find(|(n, _)| *n == name) {
+            return Ok(Atom {
+                mathml: format!("<mi>{f}</mi>"),
+                limits: true,
+            });
+        }
+        if let Some(&(_, accent, stretchy)) = ACCENTS.iter().find(|(n, _, _)| *n == name) {
+            let base = self.argument()?;
+            return Ok(Atom::new(format!(
+                r#"<mover accent="true">{base}<mo stretchy="{stretchy}">{accent}</mo></mover>"#
+            )));
+        }
+
+        let mathml = match name {
+            "frac" | "dfrac" | "tfrac" | "cfrac" => {
+                let (num, den) = (self.argument()?, self.argument()?);
+                let frac = format!("<mfrac>{num}{den}</mfrac>");
+                match name {
+                    "dfrac" | "cfrac" => format!(r#"<mstyle displaystyle="true">{frac}</mstyle>"#),
+                    "tfrac" => format!(r#"<mstyle displaystyle="false">{frac}</mstyle>"#),
+                    _ => frac,
+                }
+            }
+            "binom" | "dbinom" | "tbinom" => {
+                let (n, k) = (self.argument()?, self.argument()?);
+                format!(
+                    r#"<mrow><mo>(</mo><mfrac linethickness="0">{n}{k}</mfrac><mo>)</mo></mrow>"#
+                )
+            }
+            "sqrt" => match self.optional_argument()? {
+                Some(index) => format!("<mroot>{}{index}</mroot>", self.argument()?),
+                None => format!("<msqrt>{}</msqrt>", self.argument()?),
+            },
+            "overset" | "stackrel" => {
+                let (over, base) = (self.argument()?, self.argument()?);
+                format!("<mover>{base}{over}</mover>")
+            }
+            "underset" => {
+                let (under, base) = (self.argument()?, self.argument()?);
+                format!("<munder>{base}{under}</munder>")
+            }
+            "underline" => format!(
+                r#"<munder accentunder="true">{}<mo stretchy="true">_</mo></munder>"#,
+                self.argument()?
+            ),
+            "overbrace" | "underbrace" => {
+                let base = self.argument()?;
+                return Ok(Atom {
+                    mathml: if name == "overbrace" {
+                        format!(r#"<mover accent="true">{base}<mo stretchy="true">⏞</mo></mover>"#)
+                    } else {
+                        format!(
+                            r#"<munder accentunder="true">{base}<mo stretchy="true">⏟</mo></munder>"#
+                        )
+                    },
+                    limits: true,
+                });
+            }
+            "text" | "textrm" | "textit" | "textbf" | "textsf" | "texttt" | "textup" | "mbox"
+            | "textnormal" => {
+                let text = self.text_argument()?;
+                // Leading and trailing whitespace would be dropped otherwise.
+                let text = escape(&text);
+                let trimmed = text.trim();
+                let pad = |s: &str| if s.is_empty() { "" } else { "&#xA0;" };
+                let start = pad(&text[..text.len() - text.trim_start().len()]);
+                let end = pad(&text[text.trim_end().len()..]);
+                let variant = match name {
+                    "textit" => r#" mathvariant="italic""#,
+                    "textbf" => r#" mathvariant="bold""#,
+                    "textsf" => r#" mathvariant="sans-serif""#,
+                    "texttt" => r#" mathvariant="monospace""#,
+                    _ => "",
+                };
+                format!("<mtext{variant}>{start}{trimmed}{end}</mtext>")
+            }
+            "operatorname" => {
+                let limits = self.peek() == Some(&Token::Char('*'));
+                if limits {
+                    self.next();
+                }
+                let name = self.text_argument()?;
+                return Ok(Atom {
+                    mathml: format!("<mi>{}</mi>", escape(&name)),
+                    limits,
+                });
+            }
+            // Scripts of custom operators like `\mathop{\arg\max}_a` go below and above them.
+            "mathop" => {
+                return Ok(Atom {
+                    mathml: self.argument()?,
+                    limits: true,
+                })
+            }
+            "mathrm" | "rm" | "mathup" => self.with_variant(Variant::Normal)?,
+            "mathbf" | "bf" => self.with_variant(Variant::Bold)?,
+            "boldsymbol" | "bm" => self.with_variant(Variant::BoldItalic)?,
+            "mathit" => {
+                let previous = self.variant.take();
+                let argument = self.argument();
+                self.variant = previous;
+                argument?
+            }
+            "mathcal" | "mathscr" => self.with_variant(Variant::Script)?,
+            "mathfrak" => self.with_variant(Variant::Fraktur)?,
+            "mathbb" => self.with_variant(Variant::DoubleStruck)?,
+            "mathsf" => self.with_variant(Variant::SansSerif)?,
+            "mathtt" => self.with_variant(Variant::Monospace)?,
+            "left" => {
+                let open = self.delimiter()?;
+                let inner = self.row()?;
+                if !self.peek_command("right") {
+                    bail!("\\left without matching \\right");
+                }
+                self.next();
+                let close = self.delimiter()?;
+                format!(
+                    r#"<mrow><mo fence="true" stretchy="true">{open}</mo>{inner}<mo fence="true" stretchy="true">{close}</mo></mrow>"#
+                )
+            }
+            "middle" => format!(r#"<mo stretchy="true">{}</mo>"#, self.delimiter()?),
+            "big" | "Big" | "bigg" | "Bigg" | "bigl" | "Bigl" | "biggl" | "Biggl" | "bigr"
+            | "Bigr" | "biggr" | "Biggr" | "bigm" | "Bigm" | "biggm" | "Biggm" => {
+                let size = match name.trim_end_matches(['l', 'r', 'm']) {
+                    "big" => "1.2em",
+                    "Big" => "1.8em",
+                    "bigg" => "2.4em",
+                    _ => "3em",
+                };
+                format!(
+                    r#"<mo minsize="{size}" maxsize="{size}">{}</mo>"#,
+                    self.delimiter()?
+                )
+            }
+            "xrightarrow" | "xleftarrow" | "xRightarrow" | "xLeftarrow" | "xleftrightarrow"
+            | "xmapsto" => {
+                let arrow = match name {
+                    "xrightarrow" => "→",
+                    "xleftarrow" => "←",
+                    "xRightarrow" => "⇒",
+                    "xLeftarrow" => "⇐",
+                    "xleftrightarrow" => "↔",
+                    _ => "↦",
+                };
+                let arrow = format!(r#"<mo stretchy="true" minsize="2em">{arrow}</mo>"#);
+                match (self.optional_argument()?, self.argument()?) {
+                    (Some(under), over) => format!("<munderover>{arrow}{under}{over}</munderover>"),
+                    (None, over) => format!("<mover>{arrow}{over}</mover>"),
+                }
+            }
+            "bmod" => r#"<mo lspace="0.2222em" rspace="0.2222em">mod</mo>"#.to_owned(),
+            "pmod" => format!(
+                r#"<mrow><mspace width="1em"/><mo>(</mo><mi>mod</mi><mspace width="0.3333em"/>{}<mo>)</mo></mrow>"#,
+                self.argument()?
+            ),
+            "mod" => format!(
+                r#"<mrow><mspace width="1em"/><mi>mod</mi><mspace width="0.3333em"/>{}</mrow>"#,
+                self.argument()?
+            ),
+            // Equation numbers, placed at the end of the formula.
+            "tag" => {
+                let bare = self.peek() == Some(&Token::Char('*'));
+                if bare {
+                    self.next();
+                }
+                let tag = escape(&self.text_argument()?);
+                let tag = if bare { tag } else { format!("({tag})") };
+                format!(r#"<mrow><mspace width="2em"/><mtext>{tag}</mtext></mrow>"#)
+            }
+            "hspace" | "mspace" => {
+                if name == "hspace" && self.peek() == Some(&Token::Char('*')) {
+                    self.next();
+                }
+                let length = self.text_argument()?;
+                format!(r#"<mspace width="{}"/>"#, width(&length)?)
+            }
+            "limits" | "nolimits" => bail!("\\{name} must follow an operator"),
+            "not" => match self.next() {
+                Some(Token::Char('=')) => "<mo>≠</mo>".to_owned(),
+                Some(Token::Command(c)) if c == "in" => "<mo>∉</mo>".to_owned(),
+                Some(Token::Char(c)) => format!("<mo>{}\u{338}</mo>", escape(&c.to_string())),
+                Some(Token::Command(c)) => match OPERATORS.iter().find(|(n, _)| *n == c) {
+                    Some((_, o)) => format!("<mo>{o}\u{338}</mo>"),
+                    None => bail!(Unsupported(format!("can't negate \\{c}"))),
+                },
+                _ => bail!("missing operator after \\not"),
+            },
+            "begin" => self.environment()?,
+            "," | "thinspace" => r#"<mspace width="0.1667em"/>"#.to_owned(),
+            ":" | ">" | "medspace" => r#"<mspace width="0.2222em"/>"#.to_owned(),
+            ";" | "thickspace" => r#"<mspace width="0.2778em"/>"#.to_owned(),
+            "!" | "negthinspace" => r#"<mspace width="-0.1667em"/>"#.to_owned(),
+            "enspace" => r#"<mspace width="0.5em"/>"#.to_owned(),
+            "quad" => r#"<mspace width="1em"/>"#.to_owned(),
+            "qquad" => r#"<mspace width="2em"/>"#.to_owned(),
+            " " => "<mtext>&#xA0;</mtext>".to_owned(),
+            "{" | "lbrace" => "<mo>{</mo>".to_owned(),
+            "}" | "rbrace" => "<mo>}</mo>".to_owned(),
+            "|" => "<mo>‖</mo>".to_owned(),
+            "%" | "#" => format!("<mo>{name}</mo>"),
+            "&" => "<mo>&amp;</mo>".to_owned(),
+            "$" | "_" => format!("<mi>{name}</mi>"),
+            _ => bail!(Unsupported(format!("unsupported TeX command \\{name}"))),
+        };
+        Ok(Atom::new(mathml))
+    }
+
+    /// The delimiter after `\left`, `\right`, `\big` and friends.
+    fn delimiter(&mut self) -> Result<String> {
+        let delimiter = match self.next() {
+            Some(Token::Char('.')) => "",
+            Some(Token::Char('<')) => "⟨",
+            Some(Token::Char('>')) => "⟩",
+            Some(Token::Char(c)) if "()[]|/".contains(c) => return Ok(c.to_string()),
+            Some(Token::Command(c)) => match c.as_str() {
+                "{" | "lbrace" => "{",
+                "}" | "rbrace" => "}",
+                "|" | "Vert" | "lVert" | "rVert" => "‖",
+                "vert" | "lvert" | "rvert" => "|",
+                "langle" => "⟨",
+                "rangle" => "⟩",
+                "lfloor" => "⌊",
+                "rfloor" => "⌋",
+                "lceil" => "⌈",
+                "rceil" => "⌉",
+                "lbrack" => "[",
+                "rbrack" => "]",
+                "uparrow" => "↑",
+                "downarrow" => "↓",
+                "backslash" => "∖",
+                _ => bail!("\\{c} is not a delimiter"),
+            },
+            Some(t) => bail!("{} is not a delimiter", t.describe()),
+            None => bail!("missing delimiter"),
+        };
+        Ok(delimiter.to_owned())
+    }
+
+    /// `\begin{name} ... \end{name}`, after the `\begin`.
+    fn environment(&mut self) -> Result<String> {
+        let name = self.text_argument()?;
+        let mut columns = vec![];
+        if name == "array" || name == "alignedat" {
+            // Column alignment like `{lcr}`, or the number of columns.
+            let spec = self.text_argument()?;
+            columns = spec
+                .chars()
+                .filter_map(|c| match c {
+                    'l' => Some("left"),
+                    'c' => Some("center"),
+                    'r' => Some("right"),
+                    _ => None,
+                })
+                .collect();
+        }
+
+        let rows = self.table_rows()?;
+        if !self.peek_command("end") {
+            bail!("\\begin{{{name}}} is never ended");
+        }
+        self.next();
+        let end = self.text_argument()?;
+        if end != name {
+            bail!("\\begin{{{name}}} ended by \\end{{{end}}}");
+        }
+
+        let fenced = |open: &str, close: &str, table: String| {
+            format!(
+                r#"<mrow><mo fence="true" stretchy="true">{open}</mo>{table}<mo fence="true" stretchy="true">{close}</mo></mrow>"#
+            )
+        };
+        Ok(match name.trim_end_matches('*') {
+            "matrix" | "smallmatrix" => mtable(rows, &["center"], false),
+            "pmatrix" => fenced("(", ")", mtable(rows, &["center"], false)),
+            "bmatrix" => fenced("[", "]", mtable(rows, &["center"], false)),
+            "Bmatrix" => fenced("{", "}", mtable(rows, &["center"], false)),
+            "vmatrix" => fenced("|", "|", mtable(rows, &["center"], false)),
+            "Vmatrix" => fenced("‖", "‖", mtable(rows, &["center"], false)),
+            "cases" => fenced("{", "", mtable(rows, &["left"], false)),
+            "array" => mtable(rows, &columns, false),
+            "aligned" | "align" | "alignat" | "alignedat" | "split" | "eqnarray" => {
+                mtable(rows, &["right", "left"], true)
+            }
+            "gathered" | "gather" => mtable(rows, &["center"], true),
+            _ => bail!(Unsupported(format!("unsupported environment {name}"))),
+        })
+    }
+}
+
+/// A TeX length like `1em` or `-3mu` as a MathML width.
+fn width(length: &str) -> Result<String> {
+    let length = length.replace(' ', "");
+    let unit_start = length
+        .find(|c: char| c.is_ascii_alphabetic())
+        .unwrap_or(length.len());
+    let (number, unit) = length.split_at(unit_start);
+    let Ok(number) = number.parse::<f64>() else {
+        bail!("invalid length {length:?}");
+    };
+    Ok(match unit {
+        // 18 math units make up an em.
+        "mu" => format!("{:.4}em", number / 18.0),
+        "em" | "ex" | "pt" | "pc" | "px" | "in" | "cm" | "mm" => format!("{number}{unit}"),
+        _ => bail!("invalid length {length:?}"),
+    })
+}
+
+fn mrow(items: Vec<String>) -> String {
+    if items.len() == 1 {
+        return items.into_iter().next().unwrap();
+    }
+    format!("<mrow>{}</mrow>", items.concat())
+}
+
+/// A table, with columns aligned by cycling through `align`.
+fn mtable(rows: Vec<Vec<String>>, align: &[&str], display: bool) -> String {
+    let columns = rows.iter().map(|r| r.len()).max().unwrap_or(0);
+    let align: Vec<&str> = (0..columns)
+        .map(|i| *align.get(i % align.len().max(1)).unwrap_or(&"center"))
+        .collect();
+    let mut table = format!(r#"<mtable columnalign="{}""#, align.join(" "));
+    if display {
+        table.push_str(r#" displaystyle="true""#);
+    }
+    table.push('>');
+    for row in rows {
+        table.push_str("<mtr>");
+        for cell in row {
+            table.push_str(&format!("<mtd>{cell}</mtd>"));
+        }
+        table.push_str("</mtr>");
+    }
+    table.push_str("</mtable>");
+    table
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+const GREEK: &[(&str, &str)] = &[
+    ("alpha", "α"),
+    ("beta", "β"),
+    ("gamma", "γ"),
+    ("delta", "δ"),
+    ("epsilon", "ϵ"),
+    ("varepsilon", "ε"),
+    ("zeta", "ζ"),
+    ("eta", "η"),
+    ("theta", "θ"),
+    ("vartheta", "ϑ"),
+    ("iota", "ι"),
+    ("kappa", "κ"),
+    ("lambda", "λ"),
+    ("mu", "μ"),
+    ("nu", "ν"),
+    ("xi", "ξ"),
+    ("omicron", "ο"),
+    ("pi", "π"),
+    ("varpi", "ϖ"),
+    ("rho", "ρ"),
+    ("varrho", "ϱ"),
+    ("sigma", "σ"),
+    ("varsigma", "ς"),
+    ("tau", "τ"),
+    ("upsilon", "υ"),
+    ("phi", "ϕ"),
+    ("varphi", "φ"),
+    ("chi", "χ"),
+    ("psi", "ψ"),
+    ("omega", "ω"),
+    ("Gamma", "Γ"),
+    ("Delta", "Δ"),
+    ("Theta", "Θ"),
+    ("Lambda", "Λ"),
+    ("Xi", "Ξ"),
+    ("Pi", "Π"),
+    ("Sigma", "Σ"),
+    ("Upsilon", "Υ"),
+    ("Phi", "Φ"),
+    ("Psi", "Ψ"),
+    ("Omega", "Ω"),
+];
+
+/// Variants of Greek letters after ω in the bold alphabets.
+const GREEK_SYMBOLS: &str = "∂ϵϑϰϕϱϖ";
+
+const IDENTIFIERS: &[(&str, &str)] = &[
+    ("infty", "∞"),
+    ("partial", "∂"),
+    ("nabla", "∇"),
+    ("emptyset", "∅"),
+    ("varnothing", "∅"),
+    ("ell", "ℓ"),
+    ("hbar", "ℏ"),
+    ("aleph", "ℵ"),
+    ("Re", "ℜ"),
+    ("Im", "ℑ"),
+    ("imath", "ı"),
+    ("jmath", "ȷ"),
+    ("wp", "℘"),
+    ("top", "⊤"),
+    ("bot", "⊥"),
+    ("angle", "∠"),
+    ("triangle", "△"),
+    ("prime", "′"),
+    ("dagger", "†"),
+];
+
+const OPERATORS: &[(&str, &str)] = &[
+    ("cdot", "⋅"),
+    ("cdotp", "⋅"),
+    ("times", "×"),
+    ("div", "÷"),
+    ("pm", "±"),
+    ("mp", "∓"),
+    ("ast", "∗"),
+    ("star", "⋆"),
+    ("circ", "∘"),
+    ("intercal", "⊺"),
+    ("bullet", "∙"),
+    ("oplus", "⊕"),
+    ("ominus", "⊖"),
+    ("otimes", "⊗"),
+    ("oslash", "⊘"),
+    ("odot", "⊙"),
+    ("cap", "∩"),
+    ("cup", "∪"),
+    ("sqcap", "⊓"),
+    ("sqcup", "⊔"),
+    ("uplus", "⊎"),
+    ("wedge", "∧"),
+    ("land", "∧"),
+    ("vee", "∨"),
+    ("lor", "∨"),
+    ("neg", "¬"),
+    ("lnot", "¬"),
+    ("setminus", "∖"),
+    ("smallsetminus", "∖"),
+    ("diamond", "⋄"),
+    ("forall", "∀"),
+    ("exists", "∃"),
+    ("nexists", "∄"),
+    ("leq", "≤"),
+    ("le", "≤"),
+    ("geq", "≥"),
+    ("ge", "≥"),
+    ("leqslant", "⩽"),
+    ("geqslant", "⩾"),
+    ("neq", "≠"),
+    ("ne", "≠"),
+    ("lt", "<"),
+    ("gt", ">"),
+    ("ll", "≪"),
+    ("gg", "≫"),
+    ("approx", "≈"),
+    ("sim", "∼"),
+    ("simeq", "≃"),
+    ("cong", "≅"),
+    ("equiv", "≡"),
+    ("doteq", "≐"),
+    ("asymp", "≍"),
+    ("propto", "∝"),
+    ("triangleq", "≜"),
+    ("coloneqq", "≔"),
+    ("prec", "≺"),
+    ("succ", "≻"),
+    ("preceq", "⪯"),
+    ("succeq", "⪰"),
+    ("in", "∈"),
+    ("notin", "∉"),
+    ("ni", "∋"),
+    ("subset", "⊂"),
+    ("supset", "⊃"),
+    ("subseteq", "⊆"),
+    ("supseteq", "⊇"),
+    ("subsetneq", "⊊"),
+    ("supsetneq", "⊋"),
+    ("perp", "⊥"),
+    ("parallel", "∥"),
+    ("mid", "∣"),
+    ("vert", "|"),
+    ("Vert", "‖"),
+    ("lvert", "|"),
+    ("rvert", "|"),
+    ("lVert", "‖"),
+    ("rVert", "‖"),
+    ("vdash", "⊢"),
+    ("models", "⊨"),
+    ("to", "→"),
+    ("rightarrow", "→"),
+    ("leftarrow", "←"),
+    ("gets", "←"),
+    ("leftrightarrow", "↔"),
+    ("Rightarrow", "⇒"),
+    ("Leftarrow", "⇐"),
+    ("Leftrightarrow", "⇔"),
+    ("longrightarrow", "⟶"),
+    ("longleftarrow", "⟵"),
+    ("Longrightarrow", "⟹"),
+    ("Longleftarrow", "⟸"),
+    ("implies", "⟹"),
+    ("impliedby", "⟸"),
+    ("iff", "⟺"),
+    ("mapsto", "↦"),
+    ("longmapsto", "⟼"),
+    ("hookrightarrow", "↪"),
+    ("uparrow", "↑"),
+    ("downarrow", "↓"),
+    ("langle", "⟨"),
+    ("rangle", "⟩"),
+    ("lfloor", "⌊"),
+    ("rfloor", "⌋"),
+    ("lceil", "⌈"),
+    ("rceil", "⌉"),
+    ("lbrack", "["),
+    ("rbrack", "]"),
+    ("backslash", "∖"),
+    ("colon", ":"),
+    ("therefore", "∴"),
+    ("because", "∵"),
+    ("ldots", "…"),
+    ("dots", "…"),
+    ("cdots", "⋯"),
+    ("vdots", "⋮"),
+    ("ddots", "⋱"),
+];
+
+/// Large operators, and whether their scripts go below and above them.
+const LARGE_OPERATORS: &[(&str, &str, bool)] = &[
+    ("sum", "∑", true),
+    ("prod", "∏", true),
+    ("coprod", "∐", true),
+    ("bigcup", "⋃", true),
+    ("bigcap", "⋂", true),
+    ("bigsqcup", "⨆", true),
+    ("bigvee", "⋁", true),
+    ("bigwedge", "⋀", true),
+    ("bigoplus", "⨁", true),
+    ("bigotimes", "⨂", true),
+    ("bigodot", "⨀", true),
+    ("int", "∫", false),
+    ("iint", "∬", false),
+    ("iiint", "∭", false),
+    ("oint", "∮", false),
+];
+
+const FUNCTIONS: &[&str] = &[
+    "sin", "cos", "tan", "cot", "sec", "csc", "arcsin", "arccos", "arctan", "sinh", "cosh", "tanh",
+    "coth", "log", "ln", "lg", "exp", "arg", "dim", "ker", "deg", "hom",
+];
+
+/// Functions whose scripts go below them, like `\lim_{x \to 0}`.
+const LIMIT_FUNCTIONS: &[(&str, &str)] = &[
+    ("lim", "lim"),
+    ("limsup", "lim sup"),
+    ("liminf", "lim inf"),
+    ("max", "max"),
+    ("min", "min"),
+    ("sup", "sup"),
+    ("inf", "inf"),
+    ("det", "det"),
+    ("gcd", "gcd"),
+    ("Pr", "Pr"),
+    ("argmax", "arg max"),
+    ("argmin", "arg min"),
+];
+
+/// Accents, and whether they stretch over their whole argument.
+const ACCENTS: &[(&str, &str, bool)] = &[
+    ("hat", "^", false),
+    ("widehat", "^", true),
+    ("check", "ˇ", false),
+    ("tilde", "~", false),
+    ("widetilde", "~", true),
+    ("acute", "´", false),
+    ("grave", "`", false),
+    ("dot", "˙", false),
+    ("ddot", "¨", false),
+    ("breve", "˘", false),
+    ("bar", "‾", false),
+    ("overline", "‾", true),
+    ("vec", "→", false),
+    ("overrightarrow", "→", true),
+    ("overleftarrow", "←", true),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The MathML of inline `tex`, without the surrounding `<math>` and annotation.
+    fn mathml(tex: &str) -> String {
+        let mathml = to_mathml(tex, false).unwrap();
+        let start = mathml.find("<semantics>").unwrap() + "<semantics>".len();
+        let end = mathml.find("<annotation").unwrap();
+        mathml[start..end].to_owned()
+    }
+
+    #[test]
+    fn scripts_and_numbers() {
+        for (tex, expected) in [
+            ("x", "<mi>x</mi>"),
+            ("12.5", "<mn>12.5</mn>"),
+            ("x^2", "<msup><mi>x</mi><mn>2</mn></msup>"),
+            ("x_i^2", "<msubsup><mi>x</mi><mi>i</mi><mn>2</mn></msubsup>"),
+            // Only a single digit without braces, like in TeX.
+            (
+                "x^10",
+                "<mrow><msup><mi>x</mi><mn>1</mn></msup><mn>0</mn></mrow>",
+            ),
+            ("a_{10}", "<msub><mi>a</mi><mn>10</mn></msub>"),
+            ("x''", "<msup><mi>x</mi><mo>′′</mo></msup>"),
+            (
+                "f'(x)",
+                "<mrow><msup><mi>f</mi><mo>′</mo></msup><mo>(</mo><mi>x</mi><mo>)</mo></mrow>",
+            ),
+            ("-a", "<mrow><mo>−</mo><mi>a</mi></mrow>"),
+            ("a < b", "<mrow><mi>a</mi><mo>&lt;</mo><mi>b</mi></mrow>"),
+            (
+                "x % comment\n+ y",
+                "<mrow><mi>x</mi><mo>+</mo><mi>y</mi></mrow>",
+            ),
+        ] {
+            assert_eq!(mathml(tex), expected, "{tex}");
+        }
+    }
+
+    #[test]
+    fn symbols_and_operators() {
+        for (tex, expected) in [
+            (
+                r"\alpha + \Gamma",
+                r#"<mrow><mi>α</mi><mo>+</mo><mi mathvariant="normal">Γ</mi></mrow>"#,
+            ),
+            (r"a \not= b", "<mrow><mi>a</mi><mo>≠</mo><mi>b</mi></mrow>"),
+            (r"a \not\in B", "<mrow><mi>a</mi><mo>∉</mo><mi>B</mi></mrow>"),
+            (r"\{ x \}", "<mrow><mo>{</mo><mi>x</mi><mo>}</mo></mrow>"),
+            (
+                r"x \, y \quad z",
+                r#"<mrow><mi>x</mi><mspace width="0.1667em"/><mi>y</mi><mspace width="1em"/><mi>z</mi></mrow>"#,
+            ),
+            (
+                r"\sum_{i=1}^n i",
+                r#"<mrow><munderover><mo largeop="true">∑</mo><mrow><mi>i</mi><mo>=</mo><mn>1</mn></mrow><mi>n</mi></munderover><mi>i</mi></mrow>"#,
+            ),
+            (
+                r"\int_0^1 f",
+                r#"<mrow><msubsup><mo largeop="true">∫</mo><mn>0</mn><mn>1</mn></msubsup><mi>f</mi></mrow>"#,
+            ),
+            (r"\sin x", "<mrow><mi>sin</mi><mi>x</mi></mrow>"),
+            (
+                r"\lim_{x \to 0}",
+                "<munder><mi>lim</mi><mrow><mi>x</mi><mo>→</mo><mn>0</mn></mrow></munder>",
+            ),
+            (
+                r"\mathop{\arg\max}_a f",
+                "<mrow><munder><mrow><mi>arg</mi><mi>max</mi></mrow><mi>a</mi></munder><mi>f</mi></mrow>",
+            ),
+            (
+                r"\operatorname{softmax}(x)",
+                "<mrow><mi>softmax</mi><mo>(</mo><mi>x</mi><mo>)</mo></mrow>",
+            ),
+            (
+                r"\operatorname*{arg\,min}_x",
+                "<munder><mi>arg min</mi><mi>x</mi></munder>",
+            ),
+            (
+                r"\text{ if } x",
+                "<mrow><mtext>&#xA0;if&#xA0;</mtext><mi>x</mi></mrow>",
+            ),
+            (
+                r"\displaystyle \sum_i x",
+                r#"<mstyle displaystyle="true"><mrow><munder><mo largeop="true">∑</mo><mi>i</mi></munder><mi>x</mi></mrow></mstyle>"#,
+            ),
+        ] {
+            assert_eq!(mathml(tex), expected, "{tex}");
+        }
+    }
+
+    #[test]
+    fn fractions_and_roots() {
+        for (tex, expected) in [
+            (r"\frac{a}{b}", "<mfrac><mi>a</mi><mi>b</mi></mfrac>"),
+            (
+                r"\dfrac12",
+                r#"<mstyle displaystyle="true"><mfrac><mn>1</mn><mn>2</mn></mfrac></mstyle>"#,
+            ),
+            (
+                r"\binom{n}{k}",
+                r#"<mrow><mo>(</mo><mfrac linethickness="0"><mi>n</mi><mi>k</mi></mfrac><mo>)</mo></mrow>"#,
+            ),
+            (r"\sqrt{x}", "<msqrt><mi>x</mi></msqrt>"),
+            (r"\sqrt[3]{x}", "<mroot><mi>x</mi><mn>3</mn></mroot>"),
+        ] {
+            assert_eq!(mathml(tex), expected, "{tex}");
+        }
+    }
+
+    #[test]
+    fn accents() {
+        for (tex, expected) in [
+            (
+                r"\hat{x}",
+                r#"<mover accent="true"><mi>x</mi><mo stretchy="false">^</mo></mover>"#,
+            ),
+            (
+                r"\hat\theta",
+                r#"<mover accent="true"><mi>θ</mi><mo stretchy="false">^</mo></mover>"#,
+            ),
+            (
+                r"\widehat{xy}",
+                r#"<mover accent="true"><mrow><mi>x</mi><mi>y</mi></mrow><mo stretchy="true">^</mo></mover>"#,
+            ),
+            (
+                r"\vec v",
+                r#"<mover accent="true"><mi>v</mi><mo stretchy="false">→</mo></mover>"#,
+            ),
+            (
+                r"\bar{x}",
+                r#"<mover accent="true"><mi>x</mi><mo stretchy="false">‾</mo></mover>"#,
+            ),
+            (
+                r"\overline{AB}",
+                r#"<mover accent="true"><mrow><mi>A</mi><mi>B</mi></mrow><mo stretchy="true">‾</mo></mover>"#,
+            ),
+            (
+                r"\tilde a",
+                r#"<mover accent="true"><mi>a</mi><mo stretchy="false">~</mo></mover>"#,
+            ),
+            (
+                r"\ddot x",
+                r#"<mover accent="true"><mi>x</mi><mo stretchy="false">¨</mo></mover>"#,
+            ),
+        ] {
+            assert_eq!(mathml(tex), expected, "{tex}");
+        }
+    }
+
+    #[test]
+    fn alphabets() {
+        for (tex, expected) in [
+            (r"\mathbb{R}^n", "<msup><mi>ℝ</mi><mi>n</mi></msup>"),
+            (r"\mathbb{E}", "<mi>𝔼</mi>"),
+            (r"\mathbb{1}", "<mn>𝟙</mn>"),
+            (r"\mathbf{x}", "<mi>𝐱</mi>"),
+            (r"\mathcal{L}", "<mi>ℒ</mi>"),
+            (r"\mathcal{D}", "<mi>𝒟</mi>"),
+            (r"\mathfrak{g}", "<mi>𝔤</mi>"),
+            (r"\mathsf{A}", "<mi>𝖠</mi>"),
+            (r"\mathtt{1}", "<mn>𝟷</mn>"),
+            (
+                r"\mathrm{d}x",
+                r#"<mrow><mi mathvariant="normal">d</mi><mi>x</mi></mrow>"#,
+            ),
+            (
+                r"\boldsymbol{\theta \Omega \epsilon x}",
+                "<mrow><mi>𝜽</mi><mi>𝜴</mi><mi>𝝐</mi><mi>𝒙</mi></mrow>",
+            ),
+            (
+                r"\mathbf{\alpha \Gamma \varphi}",
+                "<mrow><mi>𝛂</mi><mi>𝚪</mi><mi>𝛗</mi></mrow>",
+            ),
+        ] {
+            assert_eq!(mathml(tex), expected, "{tex}");
+        }
+    }
+
+    #[test]
+    fn delimiters() {
+        for (tex, expected) in [
+            (
+                r"\left( \frac{a}{b} \right)",
+                r#"<mrow><mo fence="true" stretchy="true">(</mo><mfrac><mi>a</mi><mi>b</mi></mfrac><mo fence="true" stretchy="true">)</mo></mrow>"#,
+            ),
+            (
+                r"\left\{ x \right.",
+                r#"<mrow><mo fence="true" stretchy="true">{</mo><mi>x</mi><mo fence="true" stretchy="true"></mo></mrow>"#,
+            ),
+            (
+                r"\left\langle x \middle| y \right\rangle",
+                r#"<mrow><mo fence="true" stretchy="true">⟨</mo><mrow><mi>x</mi><mo stretchy="true">|</mo><mi>y</mi></mrow><mo fence="true" stretchy="true">⟩</mo></mrow>"#,
+            ),
+            (
+                r"\bigl( x \bigr)",
+                r#"<mrow><mo minsize="1.2em" maxsize="1.2em">(</mo><mi>x</mi><mo minsize="1.2em" maxsize="1.2em">)</mo></mrow>"#,
+            ),
+        ] {
+            assert_eq!(mathml(tex), expected, "{tex}");
+        }
+    }
+
+    #[test]
+    fn environments() {
+        for (tex, expected) in [
+            (
+                r"\begin{aligned} a &= b \\ c &= d \end{aligned}",
+                r#"<mtable columnalign="right left" displaystyle="true"><mtr><mtd><mi>a</mi></mtd><mtd><mrow><mo>=</mo><mi>b</mi></mrow></mtd></mtr><mtr><mtd><mi>c</mi></mtd><mtd><mrow><mo>=</mo><mi>d</mi></mrow></mtd></mtr></mtable>"#,
+            ),
+            (
+                r"\begin{cases} x & x \ge 0 \\ -x & \text{otherwise} \end{cases}",
+                r#"<mrow><mo fence="true" stretchy="true">{</mo><mtable columnalign="left left"><mtr><mtd><mi>x</mi></mtd><mtd><mrow><mi>x</mi><mo>≥</mo><mn>0</mn></mrow></mtd></mtr><mtr><mtd><mrow><mo>−</mo><mi>x</mi></mrow></mtd><mtd><mtext>otherwise</mtext></mtd></mtr></mtable><mo fence="true" stretchy="true"></mo></mrow>"#,
+            ),
+            (
+                r"\begin{pmatrix} 1 & 0 \\ 0 & 1 \\ \end{pmatrix}",
+                r#"<mrow><mo fence="true" stretchy="true">(</mo><mtable columnalign="center center"><mtr><mtd><mn>1</mn></mtd><mtd><mn>0</mn></mtd></mtr><mtr><mtd><mn>0</mn></mtd><mtd><mn>1</mn></mtd></mtr></mtable><mo fence="true" stretchy="true">)</mo></mrow>"#,
+            ),
+            (
+                r"\begin{array}{lc} a & b \end{array}",
+                r#"<mtable columnalign="left center"><mtr><mtd><mi>a</mi></mtd><mtd><mi>b</mi></mtd></mtr></mtable>"#,
+            ),
+        ] {
+            assert_eq!(mathml(tex), expected, "{tex}");
+        }
+    }
+
+    #[test]
+    fn errors() {
+        for (tex, expected) in [
+            (r"\foo", r"unsupported TeX command \foo"),
+            ("x^", "missing script after ^ or _"),
+            ("x_1_2", "double subscript"),
+            ("x^1^2", "double superscript"),
+            ("{x", "expected }, but the math ended"),
+            ("x}", "unexpected }"),
+            (r"\frac{a}", "missing argument"),
+            (r"\sqrt[3", "missing ]"),
+            (r"\left( x", r"\left without matching \right"),
+            (r"\left\foo x \right)", r"\foo is not a delimiter"),
+            (r"\hspace{wide}", r#"invalid length "wide""#),
+            (r"\limits", r"\limits must follow an operator"),
+            (r"\begin{aligned} x", r"\begin{aligned} is never ended"),
+            (
+                r"\begin{aligned} x \end{cases}",
+                r"\begin{aligned} ended by \end{cases}",
+            ),
+            ("x \\", r"math ends with a lone \"),
+        ] {
+            match to_mathml(tex, false) {
+                Ok(mathml) => panic!("{tex} gave {mathml}"),
+                Err(e) => assert_eq!(e.to_string(), expected, "{tex}"),
+            }
+        }
+    }
+
+    #[test]
+    fn unsupported_tex() {
+        for (tex, expected) in [
+            (r"\foo", r"unsupported TeX command \foo"),
+            (r"\not\foo", r"can't negate \foo"),
+            (r"\begin{foo} x \end{foo}", "unsupported environment foo"),
+            (r"x + \frac{\foo}{2}", r"unsupported TeX command \foo"),
+        ] {
+            let e = to_mathml(tex, false).unwrap_err();
+            let unsupported = e.downcast_ref::<Unsupported>();
+            assert_eq!(unsupported.map(|u| u.0.as_str()), Some(expected), "{tex}");
+        }
+        // Invalid TeX is never just unsupported.
+        for tex in [
+            r"{\foo",
+            r"\left( \foo",
+            r"\foo }",
+            r"\begin{foo} x \end{bar}",
+            "x^",
+        ] {
+            let e = to_mathml(tex, false).unwrap_err();
+            assert!(e.downcast_ref::<Unsupported>().is_none(), "{tex}: {e}");
+        }
+    }
+
+    #[test]
+    fn common_commands() {
+        for (tex, expected) in [
+            (
+                r"\sum\limits_i x",
+                r#"<mrow><munder><mo largeop="true">∑</mo><mi>i</mi></munder><mi>x</mi></mrow>"#,
+            ),
+            (
+                r"\sum\nolimits_i",
+                r#"<msub><mo largeop="true">∑</mo><mi>i</mi></msub>"#,
+            ),
+            (
+                r"\int\limits_0^1",
+                r#"<munderover><mo largeop="true">∫</mo><mn>0</mn><mn>1</mn></munderover>"#,
+            ),
+            (
+                r"\lVert x \rVert",
+                "<mrow><mo>‖</mo><mi>x</mi><mo>‖</mo></mrow>",
+            ),
+            (
+                r"a \bmod b",
+                r#"<mrow><mi>a</mi><mo lspace="0.2222em" rspace="0.2222em">mod</mo><mi>b</mi></mrow>"#,
+            ),
+            (
+                r"a \pmod{n}",
+                r#"<mrow><mi>a</mi><mrow><mspace width="1em"/><mo>(</mo><mi>mod</mi><mspace width="0.3333em"/><mi>n</mi><mo>)</mo></mrow></mrow>"#,
+            ),
+            (
+                r"x \tag{1.2}",
+                r#"<mrow><mi>x</mi><mrow><mspace width="2em"/><mtext>(1.2)</mtext></mrow></mrow>"#,
+            ),
+            (
+                r"x \tag*{A}",
+                r#"<mrow><mi>x</mi><mrow><mspace width="2em"/><mtext>A</mtext></mrow></mrow>"#,
+            ),
+            (r"\hspace{1.5em}", r#"<mspace width="1.5em"/>"#),
+            (r"\hspace*{-2 pt}", r#"<mspace width="-2pt"/>"#),
+            (r"\mspace{9mu}", r#"<mspace width="0.5000em"/>"#),
+            (
+                r"\textsf{Adam}",
+                r#"<mtext mathvariant="sans-serif">Adam</mtext>"#,
+            ),
+            (r"\texttt{x}", r#"<mtext mathvariant="monospace">x</mtext>"#),
+            (r"A^\intercal", "<msup><mi>A</mi><mo>⊺</mo></msup>"),
+            (
+                r"\xrightarrow{f}",
+                r#"<mover><mo stretchy="true" minsize="2em">→</mo><mi>f</mi></mover>"#,
+            ),
+            (
+                r"\xleftarrow[g]{f}",
+                r#"<munderover><mo stretchy="true" minsize="2em">←</mo><mi>g</mi><mi>f</mi></munderover>"#,
+            ),
+        ] {
+            assert_eq!(mathml(tex), expected, "{tex}");
+        }
+    }
+
+    #[test]
+    fn wrapper() {
+        assert_eq!(
+            to_mathml(" a<b ", true).unwrap(),
+            r#"<math xmlns="http://www.w3.org/1998/Math/MathML" display="block"><semantics><mrow><mi>a</mi><mo>&lt;</mo><mi>b</mi></mrow><annotation encoding="application/x-tex">a&lt;b</annotation></semantics></math>"#
+        );
+    }
+}
@@ -1,16 +1,110 @@
+use crate::cache::BuildCache;
 use crate::{Article, Config};
 use anyhow::Result;
-use std::path::Path;
+use lol_html::html_content::Element;
+use lol_html::{element, HandlerResult, RewriteStrSettings};
+use serde::{Deserialize, Serialize};
+use url::Url;
 
-pub fn feeds(cfg: &Config, articles: &Vec<Article>, output_path: &Path) -> Result<()> {
-    rss(cfg, articles, output_path)?;
-    atom(cfg, articles, output_path)?;
+/// What the entries of a feed contain, set per feed in the config.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FeedContent {
+    // Only the summary, readers follow the link for the rest.
+    #[default]
+    Summary,
+    // The whole post, next to a plain text description.
+    Full,
+    // The summary and the whole post. Not for JSON feeds, which have one field for HTML.
+    Both,
+}
+
+/// Writes the feeds of all posts, or of the posts with `tag`, unless they are already up to
+/// date according to `fingerprint`.
+pub fn feeds(
+    cfg: &Config,
+    cache: &BuildCache,
+    fingerprint: &str,
+    tag: Option<&str>,
+    articles: &[&Article],
+) -> Result<()> {
+    let (channel, (rss_path, rss_content), (atom_path, atom_content), (json_path, json_content)) =
+        match tag {
+            None => (
+                Channel {
+                    title: cfg.sitename.clone(),
+                    link: cfg.siteurl.clone(),
+                },
+                (cfg.feed_all_rss.clone(), cfg.feed_all_rss_content),
+                (cfg.feed_all_atom.clone(), cfg.feed_all_atom_content),
+                (cfg.feed_all_json.clone(), cfg.feed_all_json_content),
+            ),
+            Some(tag) => (
+                Channel {
+                    title: format!("{:}: {:}", cfg.sitename, tag),
+                    link: format!("{:}/tags/{:}/", cfg.siteurl, tag),
+                },
+                (tag_feed(&cfg.tag_feed_rss, tag), cfg.tag_feed_rss_content),
+                (tag_feed(&cfg.tag_feed_atom, tag), cfg.tag_feed_atom_content),
+                (tag_feed(&cfg.tag_feed_json, tag), cfg.tag_feed_json_content),
+            ),
+        };
+    cache.write(rss_path, fingerprint, || {
+        rss(cfg, &channel, articles, rss_content)
+    })?;
+    cache.write(atom_path, fingerprint, || {
+        atom(cfg, &channel, articles, atom_content)
+    })?;
+    cache.write(&json_path, fingerprint, || {
+        json(cfg, &channel, &json_path, articles, json_content)
+    })?;
 
     Ok(())
 }
 
-fn rss(cfg: &Config, articles: &Vec<Article>, output_path: &Path) -> Result<()> {
-    let items: Vec<_> = articles
+/// Path of the feed for `tag`, from a `pattern` like `tags/{tag}/feed.atom.xml`.
+pub fn tag_feed(pattern: &str, tag: &str) -> String {
+    pattern.replace("{tag}", tag)
+}
+
+/// What a feed is about.
+struct Channel {
+    title: String,
+    // The page showing the same posts.
+    link: String,
+}
+
+/// Rewrites all links and images in `html` of the article at `url` to absolute URLs, since
+/// feed readers show it away from the site.
+fn absolute_urls(html: &str, url: &str) -> Result<String> {
+    // Articles are served as `index.html` in the directory `url`.
+    let base = Url::parse(&format!("{:}/", url.trim_end_matches('/')))?;
+    let absolute = |el: &mut Element, attr: &str| -> HandlerResult {
+        if let Some(url) = el.get_attribute(attr).and_then(|v| base.join(&v).ok()) {
+            el.set_attribute(attr, url.as_str())?;
+        }
+        Ok(())
+    };
+    let html = lol_html::rewrite_str(
+        html,
+        RewriteStrSettings {
+            element_content_handlers: vec![
+                element!("[href]", |el| absolute(el, "href")),
+                element!("[src]", |el| absolute(el, "src")),
+            ],
+            ..RewriteStrSettings::new()
+        },
+    )?;
+    Ok(html)
+}
+
+fn rss(
+    cfg: &Config,
+    channel: &Channel,
+    articles: &[&Article],
+    content: FeedContent,
+) -> Result<String> {
+    let items = articles
         .iter()
         .take(cfg.max_feed_entries)
         .map(|a| {
@@ -19,36 +113,47 @@ fn rss(cfg: &Config, articles: &Vec<Article>, output_path: &Path) -> Result<()>
                 .to_str()
                 .unwrap()
                 .to_owned();
-            rss::ItemBuilder::default()
+            let description = match content {
+                FeedContent::Summary | FeedContent::Both => absolute_urls(&a.summary, &url)?,
+                FeedContent::Full => a.description.clone(),
+            };
+            let full = match content {
+                FeedContent::Summary => None,
+                FeedContent::Full | FeedContent::Both => Some(absolute_urls(&a.content, &url)?),
+            };
+            Ok(rss::ItemBuilder::default()
                 .title(a.title.clone())
                 .link(url.clone())
                 .author(cfg.author.clone())
-                .description(a.summary.clone())
+                .description(description)
+                .content(full)
                 .pub_date(a.timestamp.and_utc().to_rfc2822())
                 .guid(rss::GuidBuilder::default().value(url).build())
-                .build()
+                .build())
         })
-        .collect();
+        .collect::<Result<Vec<_>>>()?;
 
     let channel = rss::ChannelBuilder::default()
-        .title(cfg.sitename.clone())
-        .link(cfg.siteurl.clone())
+        .title(channel.title.clone())
+        .link(channel.link.clone())
         .last_build_date(chrono::Utc::now().to_rfc2822())
         .items(items)
         .build();
 
-    std::fs::create_dir_all(output_path.join(&cfg.feed_all_rss).parent().unwrap())?;
-    std::fs::write(output_path.join(&cfg.feed_all_rss), channel.to_string())?;
-
-    Ok(())
+    Ok(channel.to_string())
 }
 
-fn atom(cfg: &Config, articles: &Vec<Article>, output_path: &Path) -> Result<()> {
+fn atom(
+    cfg: &Config,
+    channel: &Channel,
+    articles: &[&Article],
+    content: FeedContent,
+) -> Result<String> {
     let author = atom_syndication::PersonBuilder::default()
         .name(cfg.author.clone())
         .build();
 
-    let entries: Vec<_> = articles
+    let entries = articles
         .iter()
         .take(cfg.max_feed_entries)
         .map(|a| {
@@ -57,37 +162,298 @@ fn atom(cfg: &Config, articles: &Vec<Article>, output_path: &Path) -> Result<()>
                 .to_str()
                 .unwrap()
                 .to_owned();
-            atom_syndication::EntryBuilder::default()
+            let summary = match content {
+                // Plain text, feed readers show it as a teaser.
+                FeedContent::Summary | FeedContent::Full => {
+                    atom_syndication::Text::plain(a.description.clone())
+                }
+                FeedContent::Both => atom_syndication::Text::html(absolute_urls(&a.summary, &url)?),
+            };
+            let full = match content {
+                FeedContent::Summary => None,
+                FeedContent::Full | FeedContent::Both => Some(
+                    atom_syndication::ContentBuilder::default()
+                        .value(absolute_urls(&a.content, &url)?)
+                        .content_type("html".to_owned())
+                        .build(),
+                ),
+            };
+            Ok(atom_syndication::EntryBuilder::default()
                 .title(a.title.clone())
                 .id(url.clone())
-                .updated(a.timestamp.and_utc())
+                .updated(a.modified.unwrap_or(a.timestamp).and_utc())
                 .author(author.clone())
                 .link(atom_syndication::LinkBuilder::default().href(url).build())
-                .summary(
-                    atom_syndication::TextBuilder::default()
-                        .value(a.summary.clone())
-                        .r#type(atom_syndication::TextType::Html)
-                        .build(),
-                )
-                .build()
+                .summary(summary)
+                .content(full)
+                .build())
         })
-        .collect();
+        .collect::<Result<Vec<_>>>()?;
 
     let feed = atom_syndication::FeedBuilder::default()
-        .title(cfg.sitename.clone())
-        .id(cfg.siteurl.clone())
+        .title(channel.title.clone())
+        .id(channel.link.clone())
         .updated(chrono::Utc::now())
         .author(author)
         .link(
             atom_syndication::LinkBuilder::default()
-                .href(cfg.siteurl.clone())
+                .href(channel.link.clone())
                 .build(),
         )
         .entries(entries)
         .build();
 
-    std::fs::create_dir_all(output_path.join(&cfg.feed_all_rss).parent().unwrap())?;
-    std::fs::write(output_path.join(&cfg.feed_all_atom), feed.to_string())?;
+    Ok(feed.to_string())
+}
 
-    Ok(())
+/// A feed in the JSON Feed 1.1 format, see https://www.jsonfeed.org/version/1.1/.
+#[derive(Serialize)]
+struct JsonFeed {
+    version: &'static str,
+    title: String,
+    home_page_url: String,
+    feed_url: String,
+    authors: Vec<JsonAuthor>,
+    items: Vec<JsonItem>,
+}
+
+#[derive(Serialize)]
+struct JsonAuthor {
+    name: String,
+}
+
+#[derive(Serialize)]
+struct JsonItem {
+    id: String,
+    url: String,
+    title: String,
+    // Plain text, like the summary of Atom feeds.
+    summary: String,
+    content_html: String,
+    date_published: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    date_modified: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
+}
+
+fn json(
+    cfg: &Config,
+    channel: &Channel,
+    path: &str,
+    articles: &[&Article],
+    content: FeedContent,
+) -> Result<String> {
+    let items = articles
+        .iter()
+        .take(cfg.max_feed_entries)
+        .map(|a| {
+            let url = std::path::PathBuf::from(&cfg.siteurl)
+                .join(&a.url)
+                .to_str()
+                .unwrap()
+                .to_owned();
+            // `Both` is rejected when the config is loaded.
+            let html = match content {
+                FeedContent::Summary => &a.summary,
+                FeedContent::Full | FeedContent::Both => &a.content,
+            };
+            Ok(JsonItem {
+                id: url.clone(),
+                content_html: absolute_urls(html, &url)?,
+                url,
+                title: a.title.clone(),
+                summary: a.description.clone(),
+                date_published: a.timestamp.and_utc().to_rfc3339(),
+                date_modified: a.modified.map(|m| m.and_utc().to_rfc3339()),
+                tags: a.tags.clone(),
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let feed = JsonFeed {
+        version: "https://jsonfeed.org/version/1.1",
+        title: channel.title.clone(),
+        home_page_url: channel.link.clone(),
+        feed_url: format!("{:}/{:}", cfg.siteurl, path),
+        authors: vec![JsonAuthor {
+            name: cfg.author.clone(),
+        }],
+        items,
+    };
+
+    Ok(serde_json::to_string_pretty(&feed)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The config of a small site, with `extra` settings.
+    fn config(extra: &str) -> Config {
+        toml::from_str(&format!(
+            r#"
+author = "Author"
+sitename = "Site"
+siteurl = "https://example.com"
+user_logo_url = ""
+content_path = "content"
+templates_path = "templates"
+feed_all_atom = "feeds/all.atom.xml"
+feed_all_rss = "feeds/all.rss.xml"
+max_feed_entries = 10
+github_user = ""
+github_access_token = ""
+{extra}
+"#
+        ))
+        .unwrap()
+    }
+
+    fn channel() -> Channel {
+        Channel {
+            title: "Site".to_owned(),
+            link: "https://example.com".to_owned(),
+        }
+    }
+
+    fn article(title: &str, modified: Option<&str>) -> Article {
+        let date = |d: &str| {
+            chrono::NaiveDate::parse_from_str(d, "%Y-%m-%d")
+                .unwrap()
+                .and_hms_opt(0, 0, 0)
+                .unwrap()
+        };
+        Article {
+            title: title.to_owned(),
+            url: format!("blog/{:}", title.to_lowercase()),
+            content: r#"<p>The <a href="img.png">whole</a> post.</p>"#.to_owned(),
+            summary: "<p>The summary.</p>".to_owned(),
+            description: "The description.".to_owned(),
+            description_html: "The description.".to_owned(),
+            headings: vec![],
+            toc: vec![],
+            meta: Default::default(),
+            links: vec![],
+            includes: vec![],
+            warnings: vec![],
+            tags: vec![],
+            timestamp: date("2024-01-02"),
+            modified: modified.map(date),
+            locale_date: String::new(),
+        }
+    }
+
+    #[test]
+    fn json_feed_has_modified_dates_only_when_given() {
+        let (first, second) = (
+            article("First", None),
+            article("Second", Some("2024-03-04")),
+        );
+        let feed = json(
+            &config(""),
+            &channel(),
+            "feeds/all.json",
+            &[&second, &first],
+            FeedContent::Summary,
+        )
+        .unwrap();
+        let feed: serde_json::Value = serde_json::from_str(&feed).unwrap();
+        let modified: Vec<_> = feed["items"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|item| (item["title"].as_str().unwrap(), item.get("date_modified")))
+            .collect();
+        assert_eq!(
+            modified,
+            [
+                ("Second", Some(&"2024-03-04T00:00:00+00:00".into())),
+                ("First", None),
+            ]
+        );
+    }
+
+    #[test]
+    fn feed_content_modes() {
+        let cfg = config("");
+        let first = article("First", None);
+        let whole = r#"<a href="https://example.com/blog/first/img.png">whole</a>"#;
+        for mode in [FeedContent::Summary, FeedContent::Full, FeedContent::Both] {
+            let rss: rss::Channel = rss(&cfg, &channel(), &[&first], mode)
+                .unwrap()
+                .parse()
+                .unwrap();
+            let item = &rss.items()[0];
+            let description = if mode == FeedContent::Full {
+                "The description."
+            } else {
+                "<p>The summary.</p>"
+            };
+            assert_eq!(item.description(), Some(description), "{mode:?}");
+            assert_eq!(
+                item.content().is_some_and(|c| c.contains(whole)),
+                mode != FeedContent::Summary,
+                "{mode:?}"
+            );
+
+            let atom: atom_syndication::Feed = atom(&cfg, &channel(), &[&first], mode)
+                .unwrap()
+                .parse()
+                .unwrap();
+            let entry = &atom.entries()[0];
+            let summary = entry.summary().unwrap();
+            if mode == FeedContent::Both {
+                assert_eq!(summary.r#type, atom_syndication::TextType::Html);
+                assert_eq!(summary.value, "<p>The summary.</p>");
+            } else {
+                assert_eq!(summary.r#type, atom_syndication::TextType::Text, "{mode:?}");
+                assert_eq!(summary.value, "The description.", "{mode:?}");
+            }
+            assert_eq!(
+                entry
+                    .content()
+                    .and_then(|c| c.value())
+                    .is_some_and(|c| c.contains(whole)),
+                mode != FeedContent::Summary,
+                "{mode:?}"
+            );
+        }
+
+        for (mode, html) in [
+            (FeedContent::Summary, "<p>The summary.</p>"),
+            (FeedContent::Full, whole),
+        ] {
+            let json: serde_json::Value = serde_json::from_str(
+                &json(&cfg, &channel(), "feeds/all.json", &[&first], mode).unwrap(),
+            )
+            .unwrap();
+            let item = &json["items"][0];
+            assert!(
+                item["content_html"].as_str().unwrap().contains(html),
+                "{item}"
+            );
+            assert_eq!(item["summary"], "The description.");
+        }
+    }
+
+    #[test]
+    fn tag_feeds_have_their_own_content() {
+        let output = tempfile::tempdir().unwrap();
+        let cache = BuildCache::open(output.path(), true).unwrap();
+        let cfg = config(r#"tag_feed_rss_content = "full""#);
+        let first = article("First", None);
+        for tag in [None, Some("t")] {
+            feeds(&cfg, &cache, "fingerprint", tag, &[&first]).unwrap();
+        }
+        let content = |path: &str| {
+            let rss: rss::Channel = std::fs::read_to_string(output.path().join(path))
+                .unwrap()
+                .parse()
+                .unwrap();
+            rss.items()[0].content().is_some()
+        };
+        assert!(!content("feeds/all.rss.xml"));
+        assert!(content("tags/t/feed.rss.xml"));
+    }
 }
@@ -0,0 +1,285 @@
+use crate::{build, load_config, BuildOptions, Config};
+use anyhow::Result;
+use axum::extract::State;
+use axum::http::{header, StatusCode, Uri};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use futures::Stream;
+use log::{error, info, warn};
+use notify::Watcher;
+use std::convert::Infallible;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+const LIVE_RELOAD_PATH: &str = "/__kaihan/livereload";
+
+/// Injected into every served HTML page, reloads the page once a rebuild finished.
+const LIVE_RELOAD_SCRIPT: &str = r#"<script>new EventSource("/__kaihan/livereload").onmessage = () => location.reload();</script>"#;
+
+struct ServeState {
+    root: PathBuf,
+    reload: broadcast::Sender<()>,
+}
+
+/// Builds the site into `output` (or a temporary directory) and serves it on localhost. The
+/// content and templates directories and `config.toml` are watched for changes, each change
+/// triggers a rebuild followed by a reload of all open pages.
+pub async fn serve(
+    blog_path: &Path,
+    siteurl: String,
+    strict: bool,
+    output: Option<PathBuf>,
+    options: BuildOptions,
+    port: u16,
+) -> Result<()> {
+    // Needs to be kept alive until the server shuts down, deletes the directory on drop.
+    let tmp_dir = tempfile::tempdir()?;
+    let render_path = output.unwrap_or_else(|| tmp_dir.path().to_path_buf());
+    let blog_path = blog_path.to_path_buf();
+    let mut config = Arc::new(load_config(&blog_path, Some(siteurl.clone()), strict)?);
+
+    if let Err(e) = rebuild(&blog_path, &config, &render_path, &options).await {
+        error!("initial build failed: {e:?}");
+    }
+
+    let (changes_tx, mut changes) = tokio::sync::mpsc::unbounded_channel();
+    let watched_blog_path = blog_path.clone();
+    let mut watcher =
+        notify::recommended_watcher(move |res: notify::Result<notify::Event>| match res {
+            Ok(e)
+                if !matches!(e.kind, notify::EventKind::Access(_))
+                    && e.paths.iter().any(|p| is_source(p, &watched_blog_path)) =>
+            {
+                let _ = changes_tx.send(());
+            }
+            Ok(_) => {}
+            Err(e) => error!("watch error: {e:?}"),
+        })?;
+    // Editors often save by replacing files, so `config.toml` is watched through its directory.
+    watcher.watch(&blog_path, notify::RecursiveMode::NonRecursive)?;
+    for dir in source_dirs(&blog_path, &config) {
+        watcher.watch(&dir, notify::RecursiveMode::Recursive)?;
+    }
+
+    let (reload, _) = broadcast::channel(16);
+    let state = Arc::new(ServeState {
+        root: render_path.clone(),
+        reload: reload.clone(),
+    });
+
+    let url = config.siteurl.clone();
+    {
+        let render_path = render_path.clone();
+        // Links that failed to archive would be fetched again on every change otherwise.
+        let options = BuildOptions {
+            clean: false,
+            offline: true,
+            ..options
+        };
+        tokio::spawn(async move {
+            while changes.recv().await.is_some() {
+                // Editors often write several files per save, wait for things to settle down.
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                while changes.try_recv().is_ok() {}
+
+                match load_config(&blog_path, Some(siteurl.clone()), strict) {
+                    Ok(new) => {
+                        let (old_dirs, new_dirs) = (
+                            source_dirs(&blog_path, &config),
+                            source_dirs(&blog_path, &new),
+                        );
+                        if old_dirs != new_dirs {
+                            for dir in old_dirs {
+                                // Fails if the directory is gone, which is fine.
+                                let _ = watcher.unwatch(&dir);
+                            }
+                            for dir in new_dirs {
+                                if let Err(e) =
+                                    watcher.watch(&dir, notify::RecursiveMode::Recursive)
+                                {
+                                    warn!("failed to watch {dir:?}: {e:?}");
+                                }
+                            }
+                        }
+                        config = Arc::new(new);
+                    }
+                    Err(e) => {
+                        error!("failed to load config.toml, keeping the previous one: {e:?}");
+                    }
+                }
+
+                info!("change detected, rebuilding");
+                match rebuild(&blog_path, &config, &render_path, &options).await {
+                    Ok(()) => {
+                        let _ = reload.send(());
+                    }
+                    Err(e) => error!("rebuild failed: {e:?}"),
+                }
+            }
+        });
+    }
+
+    let app = axum::Router::new()
+        .route(LIVE_RELOAD_PATH, axum::routing::get(live_reload))
+        .fallback(serve_file)
+        .with_state(state);
+    let listener = tokio::net::TcpListener::bind(("127.0.0.1", port)).await?;
+    println!("Serving {:?} at {:}", render_path, url);
+
+    axum::serve(listener, app)
+        .with_graceful_shutdown(async {
+            let _ = tokio::signal::ctrl_c().await;
+        })
+        .await?;
+
+    drop(tmp_dir);
+    Ok(())
+}
+
+/// The directories holding the posts and templates of the site.
+fn source_dirs(blog_path: &Path, config: &Config) -> [PathBuf; 2] {
+    [
+        blog_path.join(&config.content_path),
+        blog_path.join(&config.templates_path),
+    ]
+}
+
+/// Whether a change to `path` needs a rebuild. Of the files directly in the blog directory, only
+/// `config.toml` does, everything else there (like the output) is left alone.
+fn is_source(path: &Path, blog_path: &Path) -> bool {
+    path.parent() != Some(blog_path) || path.file_name() == Some("config.toml".as_ref())
+}
+
+async fn rebuild(
+    blog_path: &Path,
+    config: &Arc<Config>,
+    render_path: &Path,
+    options: &BuildOptions,
+) -> Result<()> {
+    let blog_path = blog_path.to_path_buf();
+    let config = config.clone();
+    let render_path = render_path.to_path_buf();
+    let options = options.clone();
+    tokio::task::spawn_blocking(move || build(&blog_path, &config, &render_path, &options)).await?
+}
+
+async fn live_reload(
+    State(state): State<Arc<ServeState>>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let stream = futures::stream::unfold(state.reload.subscribe(), |mut rx| async move {
+        match rx.recv().await {
+            Err(broadcast::error::RecvError::Closed) => None,
+            // Lagging behind still means there was at least one rebuild.
+            _ => Some((Ok(Event::default().data("reload")), rx)),
+        }
+    });
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+async fn serve_file(State(state): State<Arc<ServeState>>, uri: Uri) -> Response {
+    let Some(mut path) = local_path(&state.root, uri.path()) else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+    if path.is_dir() {
+        path = path.join("index.html");
+    }
+    let Ok(mut data) = tokio::fs::read(&path).await else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let ext = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+    if ext == "html" {
+        data = inject_live_reload(data);
+    }
+
+    ([(header::CONTENT_TYPE, content_type(ext))], data).into_response()
+}
+
+/// Where the file for the request path `uri_path` is below `root`, or `None` if it would be
+/// outside of it.
+fn local_path(root: &Path, uri_path: &str) -> Option<PathBuf> {
+    let path = percent_encoding::percent_decode_str(uri_path).decode_utf8_lossy();
+    let path = Path::new(path.trim_start_matches('/'));
+    // Don't allow escaping from the output directory.
+    if path
+        .components()
+        .any(|c| !matches!(c, Component::Normal(_)))
+    {
+        return None;
+    }
+    Some(root.join(path))
+}
+
+fn inject_live_reload(html: Vec<u8>) -> Vec<u8> {
+    let mut html = String::from_utf8_lossy(&html).into_owned();
+    match html.rfind("</body>") {
+        Some(i) => html.insert_str(i, LIVE_RELOAD_SCRIPT),
+        None => html.push_str(LIVE_RELOAD_SCRIPT),
+    }
+    html.into_bytes()
+}
+
+fn content_type(ext: &str) -> &'static str {
+    match ext.to_ascii_lowercase().as_str() {
+        "html" => "text/html; charset=utf-8",
+        "css" => "text/css",
+        "js" => "text/javascript",
+        "json" => "application/json",
+        "xml" => "application/xml",
+        "txt" => "text/plain; charset=utf-8",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "pdf" => "application/pdf",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths_stay_below_the_root() {
+        let root = Path::new("/out");
+        let path = |p| local_path(root, p);
+        assert_eq!(path("/"), Some(PathBuf::from("/out/")));
+        assert_eq!(path("/blog/post/"), Some(PathBuf::from("/out/blog/post")));
+        assert_eq!(path("/a%20b.png"), Some(PathBuf::from("/out/a b.png")));
+        assert_eq!(path("/../etc/passwd"), None);
+        assert_eq!(path("/blog/%2e%2e/%2e%2e/etc/passwd"), None);
+        assert_eq!(path("/blog/..%2f..%2fetc/passwd"), None);
+        assert_eq!(path("//etc/passwd"), Some(PathBuf::from("/out/etc/passwd")));
+        assert_eq!(path("/./index.html"), None);
+    }
+
+    #[test]
+    fn live_reload_goes_before_the_last_body_end() {
+        let inject = |html: &str| String::from_utf8(inject_live_reload(html.into())).unwrap();
+        assert_eq!(
+            inject("<body><pre></body></pre></body></html>"),
+            format!("<body><pre></body></pre>{LIVE_RELOAD_SCRIPT}</body></html>")
+        );
+        assert_eq!(
+            inject("<p>Hi</p>"),
+            format!("<p>Hi</p>{LIVE_RELOAD_SCRIPT}")
+        );
+    }
+
+    #[test]
+    fn only_sources_trigger_rebuilds() {
+        let blog = Path::new("/blog");
+        assert!(is_source(Path::new("/blog/config.toml"), blog));
+        assert!(is_source(Path::new("/blog/content/post.md"), blog));
+        assert!(is_source(Path::new("/blog/templates/base.html"), blog));
+        assert!(!is_source(Path::new("/blog/.config.toml.swp"), blog));
+        assert!(!is_source(Path::new("/blog/output"), blog));
+    }
+}